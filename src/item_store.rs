@@ -2,6 +2,7 @@
 use sync::Arc;
 
 pub trait ItemStore<K, V>: Clone+Send+Sync {
+    fn new(key: K, val: V) -> Self;
     fn key<'a>(&'a self) -> &'a K;
     fn val<'a>(&'a self) -> &'a V;
 }
@@ -21,6 +22,7 @@ impl<K: Clone+Send+Sync, V: Clone+Send+Sync> CopyStore<K, V> {
 }
 
 impl<K: Clone+Send+Sync, V: Clone+Send+Sync> ItemStore<K, V> for CopyStore<K, V> {
+    fn new(key: K, val: V) -> CopyStore<K, V> { CopyStore::new(key, val) }
     fn key<'a>(&'a self) -> &'a K { &self.key }
     fn val<'a>(&'a self) -> &'a V { &self.val }
 }
@@ -45,6 +47,7 @@ impl<K: Send+Sync, V: Send+Sync> ShareStore<K, V> {
 }
 
 impl<K: Send+Sync, V: Send+Sync> ItemStore<K, V> for ShareStore<K, V> {
+    fn new(key: K, val: V) -> ShareStore<K, V> { ShareStore::new(key, val) }
     fn key<'a>(&'a self) -> &'a K { self.store.ref0() }
     fn val<'a>(&'a self) -> &'a V { self.store.ref1() }
 }
@@ -55,4 +58,39 @@ impl<K: Send+Sync, V: Send+Sync> Clone for ShareStore<K, V> {
             store: self.store.clone()
         }
     }
-}
\ No newline at end of file
+}
+
+// Stores the key inline and the value behind an `Arc`, so that many entries can share a single
+// value allocation. Useful when there are only a few distinct values.
+pub struct InternedValueStore<K, V> {
+    key: K,
+    val: Arc<V>,
+}
+
+impl<K: Clone+Send+Sync, V: Send+Sync> InternedValueStore<K, V> {
+    pub fn new(key: K, val: V) -> InternedValueStore<K, V> {
+        InternedValueStore::from_arc(key, Arc::new(val))
+    }
+
+    pub fn from_arc(key: K, val: Arc<V>) -> InternedValueStore<K, V> {
+        InternedValueStore {
+            key: key,
+            val: val
+        }
+    }
+}
+
+impl<K: Clone+Send+Sync, V: Send+Sync> ItemStore<K, V> for InternedValueStore<K, V> {
+    fn new(key: K, val: V) -> InternedValueStore<K, V> { InternedValueStore::new(key, val) }
+    fn key<'a>(&'a self) -> &'a K { &self.key }
+    fn val<'a>(&'a self) -> &'a V { &*self.val }
+}
+
+impl<K: Clone+Send+Sync, V: Send+Sync> Clone for InternedValueStore<K, V> {
+    fn clone(&self) -> InternedValueStore<K, V> {
+        InternedValueStore {
+            key: self.key.clone(),
+            val: self.val.clone(),
+        }
+    }
+}
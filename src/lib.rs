@@ -75,6 +75,22 @@ pub trait PersistentMap<K, V>: Map<K, V> + Clone {
     }
 }
 
+/// A read-only view of a map that can be used as a trait object, e.g. as
+/// `Arc<Box<ReadOnlyMap<K, V> + Send + Sync>>`, so that different map implementations can be
+/// stored and queried behind a common interface.
+pub trait ReadOnlyMap<K, V> {
+    /// Returns a reference to the value stored for `key`, if any.
+    fn get<'a>(&'a self, key: &K) -> Option<&'a V>;
+
+    /// Returns the number of entries in the map.
+    fn len(&self) -> uint;
+
+    /// Returns true if the map contains an entry for `key`.
+    fn contains(&self, key: &K) -> bool {
+        self.get(key).is_some()
+    }
+}
+
 /// A trait to represent persistent sets. Objects implementing this trait are supposed to be
 /// cheaply copyable. Typically they can be seen as a kind of smart pointer with similar performance
 /// characteristics.
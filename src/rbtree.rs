@@ -18,14 +18,42 @@
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
 // THE SOFTWARE.
 
+use std::collections::{HashSet, PriorityQueue, RingBuf, Deque};
+use std::fmt;
 use std::hash::Hash;
-use sync::Arc;
+use std::mem;
+use std::iter::Peekable;
+use std::sync::atomics::{AtomicUint, SeqCst};
+use sync::{Arc, RWLock};
 
 use PersistentMap;
-use item_store::{ItemStore, CopyStore, ShareStore};
+use ReadOnlyMap;
+use item_store::{ItemStore, CopyStore, ShareStore, InternedValueStore};
 
-#[deriving(Clone, Eq, PartialEq)]
-enum Color {
+// Implements `PartialEq`, `Eq`, and `PartialOrd` for a type in terms of its `Ord` implementation.
+// The generic parameters of the impls go in brackets, e.g. `impl_cmp_traits!([K: Ord] Foo<K>)`.
+macro_rules! impl_cmp_traits(
+    ([$($params:tt)*] $ty:ty) => (
+        impl<$($params)*> PartialEq for $ty {
+            fn eq(&self, other: &$ty) -> bool {
+                self.cmp(other) == Equal
+            }
+        }
+
+        impl<$($params)*> Eq for $ty {}
+
+        impl<$($params)*> PartialOrd for $ty {
+            fn partial_cmp(&self, other: &$ty) -> Option<Ordering> {
+                Some(self.cmp(other))
+            }
+        }
+    );
+)
+
+// The color of a node. Nodes in a balanced tree are only ever `Red` or `Black`; `NegativeBlack`
+// and `DoubleBlack` exist transiently while a removal rebalances the tree.
+#[deriving(Clone, Eq, PartialEq, Show)]
+pub enum Color {
     NegativeBlack = 0,
     Red = 1,
     Black = 2,
@@ -56,6 +84,8 @@ struct NodeData<K, V, IS> {
     left: NodeRef<K, V, IS>,
     item: IS,
     right: NodeRef<K, V, IS>,
+    // The number of items in the subtree rooted at this node
+    size: uint,
 }
 
 struct NodeRef<K, V, IS> {
@@ -80,6 +110,7 @@ fn new_node<K: Ord+Clone+Send+Sync,
                 item: IS,
                 right: NodeRef<K, V, IS>)
              -> NodeRef<K, V, IS> {
+    let size = left.size() + 1 + right.size();
     let node = NodeRef {
         col: color,
         data: Some(
@@ -87,7 +118,8 @@ fn new_node<K: Ord+Clone+Send+Sync,
                 NodeData {
                     left: left,
                     item: item,
-                    right: right
+                    right: right,
+                    size: size
                 }
             )
         )
@@ -107,6 +139,24 @@ fn new_leaf<K: Ord+Clone+Send+Sync,
     return leaf;
 }
 
+// Returns the index of the first element of the slice `items` for which `is_less` returns false.
+// `is_less` must return true for a (possibly empty) prefix of `items` and false for the rest.
+fn lower_bound<T>(items: &[T], is_less: |&T| -> bool) -> uint {
+    let mut low = 0u;
+    let mut high = items.len();
+
+    while low < high {
+        let mid = low + (high - low) / 2;
+        if is_less(&items[mid]) {
+            low = mid + 1;
+        } else {
+            high = mid;
+        }
+    }
+
+    low
+}
+
 impl<K: Ord+Clone+Send+Sync, V: Clone+Send+Sync, IS: ItemStore<K, V>> NodeRef<K, V, IS> {
 
     fn is_leaf(&self) -> bool {
@@ -120,6 +170,118 @@ impl<K: Ord+Clone+Send+Sync, V: Clone+Send+Sync, IS: ItemStore<K, V>> NodeRef<K,
         }
     }
 
+    // The number of items in this subtree
+    fn size(&self) -> uint {
+        match self.data {
+            Some(ref data_ref) => data_ref.size,
+            None => 0
+        }
+    }
+
+    // Returns the item at position `index` in key order
+    fn select<'a>(&'a self, index: uint) -> Option<&'a IS> {
+        let mut node = self;
+        let mut index = index;
+
+        while !node.is_leaf() {
+            let node_data = node.get_data();
+            let left_size = node_data.left.size();
+
+            if index < left_size {
+                node = &node_data.left;
+            } else if index > left_size {
+                index -= left_size + 1;
+                node = &node_data.right;
+            } else {
+                return Some(&node_data.item);
+            }
+        }
+
+        None
+    }
+
+    // Returns the number of keys in this subtree that are less than `search_key`
+    fn rank(&self, search_key: &K) -> uint {
+        let mut node = self;
+        let mut rank = 0u;
+
+        while !node.is_leaf() {
+            let node_data = node.get_data();
+
+            if *search_key <= *node_data.item.key() {
+                node = &node_data.left;
+            } else {
+                rank += node_data.left.size() + 1;
+                node = &node_data.right;
+            }
+        }
+
+        rank
+    }
+
+    // Appends the largest items of this subtree to `out`, in descending order, until `out` holds
+    // `k` items. Returns the number of nodes visited.
+    fn collect_largest<'a>(&'a self, k: uint, out: &mut Vec<&'a IS>) -> uint {
+        if self.is_leaf() || out.len() >= k {
+            return 0;
+        }
+
+        let node_data = self.get_data();
+        let mut visited = 1 + node_data.right.collect_largest(k, out);
+
+        if out.len() < k {
+            out.push(&node_data.item);
+            visited += node_data.left.collect_largest(k, out);
+        }
+
+        visited
+    }
+
+    // Appends the smallest items of this subtree to `out`, in ascending order, until `out` holds
+    // `k` items. Returns the number of nodes visited.
+    fn collect_smallest<'a>(&'a self, k: uint, out: &mut Vec<&'a IS>) -> uint {
+        if self.is_leaf() || out.len() >= k {
+            return 0;
+        }
+
+        let node_data = self.get_data();
+        let mut visited = 1 + node_data.left.collect_smallest(k, out);
+
+        if out.len() < k {
+            out.push(&node_data.item);
+            visited += node_data.right.collect_smallest(k, out);
+        }
+
+        visited
+    }
+
+    // Returns the number of keys in this subtree that are greater than `search_key`
+    fn count_greater(&self, search_key: &K) -> uint {
+        let mut node = self;
+        let mut count = 0u;
+
+        while !node.is_leaf() {
+            let node_data = node.get_data();
+
+            if *search_key < *node_data.item.key() {
+                count += node_data.right.size() + 1;
+                node = &node_data.left;
+            } else {
+                node = &node_data.right;
+            }
+        }
+
+        count
+    }
+
+    // The address of the referenced node, used for identifying nodes shared between trees
+    fn node_ptr(&self) -> Option<*const NodeData<K, V, IS>> {
+        match self.data {
+            Some(ref data_ref) => Some(data_ref.deref() as *const NodeData<K, V, IS>),
+            None => None
+        }
+    }
+
     fn redden(self) -> NodeRef<K, V, IS> {
         assert!(!self.is_leaf());
         NodeRef {
@@ -146,64 +308,206 @@ impl<K: Ord+Clone+Send+Sync, V: Clone+Send+Sync, IS: ItemStore<K, V>> NodeRef<K,
     }
 
     fn find<'a>(&'a self, search_key: &K) -> Option<&'a V> {
-        match self.data {
-            Some(ref data_ref) => {
-                let data_ref = data_ref.deref();
+        let mut node = self;
 
-                if *search_key < *data_ref.item.key() {
-                    data_ref.left.find(search_key)
-                } else if *search_key > *data_ref.item.key() {
-                    data_ref.right.find(search_key)
-                } else {
-                    Some(data_ref.item.val())
-                }
+        loop {
+            let data_ref = match node.data {
+                Some(ref data_ref) => data_ref.deref(),
+                None => return None
+            };
+
+            if *search_key < *data_ref.item.key() {
+                node = &data_ref.left;
+            } else if *search_key > *data_ref.item.key() {
+                node = &data_ref.right;
+            } else {
+                return Some(data_ref.item.val());
             }
-            None => None
         }
     }
 
+    fn find_kvp<'a>(&'a self, search_key: &K) -> &'a IS {
+        assert!(!self.is_leaf());
+        let node_data = self.get_data();
+
+        if *search_key < *node_data.item.key() {
+            node_data.left.find_kvp(search_key)
+        } else if *search_key > *node_data.item.key() {
+            node_data.right.find_kvp(search_key)
+        } else {
+            &node_data.item
+        }
+    }
+
+    // Looks up a sorted slice of keys in a single descent, pushing one presence flag per key onto
+    // `result` (in the order of `keys`)
+    fn contains_sorted(&self, keys: &[K], result: &mut Vec<bool>) {
+        if keys.is_empty() {
+            return;
+        }
+
+        if self.is_leaf() {
+            result.grow(keys.len(), &false);
+            return;
+        }
+
+        let node_data = self.get_data();
+        let node_key = node_data.item.key();
+        let lower = lower_bound(keys, |key| *key < *node_key);
+        let upper = lower + keys.slice_from(lower).iter().take_while(|k| **k == *node_key).count();
+
+        node_data.left.contains_sorted(keys.slice_to(lower), result);
+        result.grow(upper - lower, &true);
+        node_data.right.contains_sorted(keys.slice_from(upper), result);
+    }
+
+    // Splits this tree into one with the items before position `index` and one with the rest
+    fn split_at_rank(&self, index: uint) -> (NodeRef<K, V, IS>, NodeRef<K, V, IS>) {
+        if self.is_leaf() {
+            return (new_leaf(Black), new_leaf(Black));
+        }
+
+        let node_data = self.get_data();
+        let left_size = node_data.left.size();
+
+        if index <= left_size {
+            let (left, right) = node_data.left.split_at_rank(index);
+            (left, join(right, node_data.item.clone(), node_data.right.clone()))
+        } else {
+            let (left, right) = node_data.right.split_at_rank(index - left_size - 1);
+            (join(node_data.left.clone(), node_data.item.clone(), left), right)
+        }
+    }
+
+    // Replaces the values of the keys in `updates`, which must be sorted by key, in a single
+    // descent. Keys not contained in the tree are ignored. Subtrees without updates are shared.
+    fn update_sorted(&self, updates: &[IS]) -> NodeRef<K, V, IS> {
+        if updates.is_empty() || self.is_leaf() {
+            return self.clone();
+        }
+
+        let node_data = self.get_data();
+        let node_key = node_data.item.key();
+        let lower = lower_bound(updates, |item| *item.key() < *node_key);
+        let upper = lower + updates.slice_from(lower)
+                                   .iter()
+                                   .take_while(|item| *item.key() == *node_key)
+                                   .count();
+
+        let left = node_data.left.update_sorted(updates.slice_to(lower));
+        let right = node_data.right.update_sorted(updates.slice_from(upper));
+
+        let item = if upper > lower {
+            // The last update for a key wins
+            updates[upper - 1].clone()
+        } else if left.node_ptr() == node_data.left.node_ptr() &&
+                  right.node_ptr() == node_data.right.node_ptr() {
+            // Only keys that are not in the tree went through this node
+            return self.clone();
+        } else {
+            node_data.item.clone()
+        };
+
+        new_node(self.col, left, item, right)
+    }
+
     // Calculates the max black nodes on path:
-    // fn count_black_height(&self, combine: |u64, u64| -> u64) -> u64 {
-    //     assert!(self.col == Red || self.col == Black);
-
-    //     match self.data {
-    //         Some(ref data_ref) => {
-    //             let data_ref = data_ref.get();
-    //             let this = if self.col == Black { 1 } else { 0 };
-    //             let sub = combine(data_ref.left.count_black_height(|a, b| combine(a, b)),
-    //                               data_ref.right.count_black_height(|a, b| combine(a, b)));
-    //             this + sub
-    //         }
-    //         None => { 1 }
-    //     }
-    // }
+    fn count_black_height(&self, combine: |uint, uint| -> uint) -> uint {
+        assert!(self.col == Red || self.col == Black);
+
+        match self.data {
+            Some(ref data_ref) => {
+                let data_ref = data_ref.deref();
+                let this = if self.col == Black { 1 } else { 0 };
+                let sub = combine(data_ref.left.count_black_height(|a, b| combine(a, b)),
+                                  data_ref.right.count_black_height(|a, b| combine(a, b)));
+                this + sub
+            }
+            None => { 1 }
+        }
+    }
 
     // Does this tree contain a red child of red?
-    // fn no_red_red(&self) -> bool {
-    //     assert!(self.col == Red || self.col == Black);
-    //     if !self.is_leaf() {
-    //         let (l, r) = self.children();
-    //         assert!(l.col == Red || l.col == Black);
-    //         assert!(r.col == Red || r.col == Black);
-
-    //         if self.col == Black {
-    //             return l.no_red_red() && r.no_red_red();
-    //         }
-
-    //         if self.col == Red && l.col == Black && r.col == Black {
-    //             return l.no_red_red() && r.no_red_red();
-    //         }
-
-    //         return false;
-    //     } else {
-    //         return true;
-    //     }
-    // }
+    fn no_red_red(&self) -> bool {
+        assert!(self.col == Red || self.col == Black);
+        if !self.is_leaf() {
+            let node_data = self.get_data();
+            let (l, r) = (&node_data.left, &node_data.right);
+            assert!(l.col == Red || l.col == Black);
+            assert!(r.col == Red || r.col == Black);
+
+            if self.col == Black {
+                return l.no_red_red() && r.no_red_red();
+            }
+
+            if self.col == Red && l.col == Black && r.col == Black {
+                return l.no_red_red() && r.no_red_red();
+            }
+
+            return false;
+        } else {
+            return true;
+        }
+    }
 
     // Is this tree black-balanced?
-    // fn black_balanced(&self) -> bool {
-    //     self.count_black_height(::std::num::max) == self.count_black_height(::std::num::min)
-    // }
+    fn black_balanced(&self) -> bool {
+        self.count_black_height(::std::cmp::max) == self.count_black_height(::std::cmp::min)
+    }
+
+    // The number of black nodes on the left spine of this tree, not counting the leaf. For a
+    // black-balanced tree this is the black height of every path.
+    fn black_height(&self) -> uint {
+        let mut height = 0u;
+        let mut node = self;
+        while !node.is_leaf() {
+            if node.col == Black {
+                height += 1;
+            }
+            node = &node.get_data().left;
+        }
+        height
+    }
+
+    // The number of nodes on the longest path from this node to a leaf, not counting the leaf
+    fn height(&self) -> uint {
+        if self.is_leaf() {
+            0
+        } else {
+            let node_data = self.get_data();
+            1 + ::std::cmp::max(node_data.left.height(), node_data.right.height())
+        }
+    }
+
+    // The number of non-leaf nodes of the given color in this subtree
+    #[cfg(test)]
+    fn count_color(&self, color: Color) -> uint {
+        if self.is_leaf() {
+            return 0;
+        }
+
+        let node_data = self.get_data();
+        (if self.col == color { 1 } else { 0 }) +
+        node_data.left.count_color(color) +
+        node_data.right.count_color(color)
+    }
+
+    // Do both trees have the same shape, colors, and keys?
+    #[cfg(test)]
+    fn structurally_eq(&self, other: &NodeRef<K, V, IS>) -> bool {
+        if self.col != other.col || self.is_leaf() != other.is_leaf() {
+            return false;
+        }
+
+        if self.is_leaf() {
+            return true;
+        }
+
+        let (data, other_data) = (self.get_data(), other.get_data());
+        *data.item.key() == *other_data.item.key() &&
+            data.left.structurally_eq(&other_data.left) &&
+            data.right.structurally_eq(&other_data.right)
+    }
 
     // Returns the maxium (key . value) pair:
     fn find_max_kvp<'a>(&'a self) -> &'a IS {
@@ -216,11 +520,28 @@ impl<K: Ord+Clone+Send+Sync, V: Clone+Send+Sync, IS: ItemStore<K, V>> NodeRef<K,
         }
     }
 
-    fn modify_at(&self, kvp: IS, insertion_count: &mut uint) -> NodeRef<K, V, IS> {
-        self.modify_at_rec(kvp, insertion_count).blacken()
+    // Returns the minimum (key . value) pair:
+    fn find_min_kvp<'a>(&'a self) -> &'a IS {
+        assert!(!self.is_leaf());
+        let node = self.get_data();
+        if node.left.is_leaf() {
+            &node.item
+        } else {
+            node.left.find_min_kvp()
+        }
+    }
+
+    // Inserts `kvp`. If the key is already present, the stored item is replaced with what `merge`
+    // makes of it and `kvp`, or left alone, along with the whole tree, if `merge` returns `None`.
+    // If `replaced` is given, the replaced item is stored in it.
+    fn modify_at(&self, kvp: IS, insertion_count: &mut uint, replaced: Option<&mut Option<IS>>,
+                 merge: &mut |&IS, IS| -> Option<IS>) -> NodeRef<K, V, IS> {
+        self.modify_at_rec(kvp, insertion_count, replaced, merge).blacken()
     }
 
-    fn modify_at_rec(&self, kvp: IS, insertion_count: &mut uint) -> NodeRef<K, V, IS> {
+    fn modify_at_rec(&self, kvp: IS, insertion_count: &mut uint,
+                     replaced: Option<&mut Option<IS>>, merge: &mut |&IS, IS| -> Option<IS>)
+                     -> NodeRef<K, V, IS> {
         if self.is_leaf() {
             assert!(self.col == Black);
             *insertion_count = 1;
@@ -232,24 +553,45 @@ impl<K: Ord+Clone+Send+Sync, V: Clone+Send+Sync, IS: ItemStore<K, V>> NodeRef<K,
             let node_data = self.get_data();
             let node_color = self.col;
 
+            // An unchanged subtree comes back as the very same node, and then so does this one
             if *kvp.key() < *node_data.item.key() {
+                let left = node_data.left.modify_at_rec(kvp, insertion_count, replaced, merge);
+                if left.node_ptr() == node_data.left.node_ptr() {
+                    return self.clone();
+                }
                 new_node(node_color,
-                         node_data.left.modify_at_rec(kvp, insertion_count),
+                         left,
                          node_data.item.clone(),
                          node_data.right.clone())
                 .balance()
             } else if *kvp.key() > *node_data.item.key() {
+                let right = node_data.right.modify_at_rec(kvp, insertion_count, replaced, merge);
+                if right.node_ptr() == node_data.right.node_ptr() {
+                    return self.clone();
+                }
                 new_node(node_color,
                          node_data.left.clone(),
                          node_data.item.clone(),
-                         node_data.right.modify_at_rec(kvp, insertion_count))
+                         right)
                 .balance()
             } else {
+                // Keys that compare `Equal` denote the same entry, even if `Eq` tells them apart.
+                // For a plain insert, `merge` returns the new item, so it replaces the stored one
+                // key included.
                 *insertion_count = 0;
-                new_node(node_color,
-                         node_data.left.clone(),
-                         kvp,
-                         node_data.right.clone())
+                match (*merge)(&node_data.item, kvp) {
+                    Some(item) => {
+                        match replaced {
+                            Some(replaced) => *replaced = Some(node_data.item.clone()),
+                            None => {}
+                        }
+                        new_node(node_color,
+                                 node_data.left.clone(),
+                                 item,
+                                 node_data.right.clone())
+                    }
+                    None => self.clone()
+                }
             }
         }
     }
@@ -396,13 +738,15 @@ impl<K: Ord+Clone+Send+Sync, V: Clone+Send+Sync, IS: ItemStore<K, V>> NodeRef<K,
         return self;
     }
 
-    // Deletes a key from this map
-    fn delete(&self, search_key: &K, removal_count: &mut uint) -> NodeRef<K, V, IS> {
+    // Deletes a key from this map. If `removed` is given, the removed item is stored in it.
+    fn delete(&self, search_key: &K, removal_count: &mut uint, removed: Option<&mut Option<IS>>)
+              -> NodeRef<K, V, IS> {
         // Finds the node to be removed
         fn del<K: Ord+Clone+Send+Sync, V: Clone+Send+Sync, IS: ItemStore<K, V>>(
             node: &NodeRef<K, V, IS>,
             search_key: &K,
-            removal_count: &mut uint)
+            removal_count: &mut uint,
+            removed: Option<&mut Option<IS>>)
          -> NodeRef<K, V, IS> {
             if !node.is_leaf() {
                 let node_data = node.get_data();
@@ -410,16 +754,20 @@ impl<K: Ord+Clone+Send+Sync, V: Clone+Send+Sync, IS: ItemStore<K, V>> NodeRef<K,
 
                 if *search_key < *node_key {
                     bubble(node.col,
-                           del(&node_data.left, search_key, removal_count),
+                           del(&node_data.left, search_key, removal_count, removed),
                            node_data.item.clone(),
                            node_data.right.clone())
                 } else if *search_key > *node_key {
                     bubble(node.col,
                            node_data.left.clone(),
                            node_data.item.clone(),
-                           del(&node_data.right, search_key, removal_count))
+                           del(&node_data.right, search_key, removal_count, removed))
                 } else {
                     *removal_count = 1;
+                    match removed {
+                        Some(removed) => *removed = Some(node_data.item.clone()),
+                        None => {}
+                    }
                     remove(node)
                 }
             } else {
@@ -428,180 +776,1957 @@ impl<K: Ord+Clone+Send+Sync, V: Clone+Send+Sync, IS: ItemStore<K, V>> NodeRef<K,
             }
         }
 
-        // Removes this node. might leave behind a double-black node:
-        fn remove<K: Ord+Clone+Send+Sync, V: Clone+Send+Sync, IS: ItemStore<K, V>>(
-            node: &NodeRef<K, V, IS>) -> NodeRef<K, V, IS> {
-            assert!(!node.is_leaf());
+        // Delete the key, and color the new root black
+        del(self, search_key, removal_count, removed).blacken()
+    }
 
-            let node_data = node.get_data();
-            let left = &node_data.left;
-            let right = &node_data.right;
+    // Same as `delete()` but without recursion: the path to the node to be removed is recorded
+    // while walking down and then rebuilt bottom-up in a loop.
+    fn delete_iterative(&self, search_key: &K, removal_count: &mut uint) -> NodeRef<K, V, IS> {
+        // The nodes on the path to the removed node, and whether the path went left at each one
+        let mut path: Vec<(&NodeRef<K, V, IS>, bool)> = Vec::new();
+        let mut node = self;
 
-            if left.is_leaf() && right.is_leaf() {
-                return if node.col == Red {
-                    new_leaf(Black)
-                } else {
-                    assert!(node.col == Black);
-                    new_leaf(DoubleBlack)
-                };
+        loop {
+            if node.is_leaf() {
+                // Nothing to remove, the tree stays the same
+                *removal_count = 0;
+                return self.clone().blacken();
             }
 
-            if node.col == Red {
-                if right.is_leaf() {
-                    return left.clone();
-                }
+            let node_data = node.get_data();
+            let node_key = node_data.item.key();
 
-                if left.is_leaf() {
-                    return right.clone();
-                }
+            if *search_key < *node_key {
+                path.push((node, true));
+                node = &node_data.left;
+            } else if *search_key > *node_key {
+                path.push((node, false));
+                node = &node_data.right;
+            } else {
+                break;
             }
+        }
 
-            if node.col == Black {
-                if left.col == Red && right.is_leaf() {
-                    let left_child_data = left.get_data();
-                    return new_node(Black,
-                                    left_child_data.left.clone(),
-                                    left_child_data.item.clone(),
-                                    left_child_data.right.clone());
-                }
+        *removal_count = 1;
+        rebuild_path(path.as_slice(), remove_iterative(node)).blacken()
+    }
+}
 
-                if left.is_leaf() && right.col == Red {
-                    let right_child_data = right.get_data();
-                    return new_node(Black,
-                                    right_child_data.left.clone(),
-                                    right_child_data.item.clone(),
-                                    right_child_data.right.clone());
-                }
+// Rebuilds the nodes on `path` (from the root downwards) bottom-up around the new subtree
+// `replacement`, moving any double-black towards the root.
+fn rebuild_path<K: Ord+Clone+Send+Sync, V: Clone+Send+Sync, IS: ItemStore<K, V>>(
+    path: &[(&NodeRef<K, V, IS>, bool)],
+    replacement: NodeRef<K, V, IS>)
+ -> NodeRef<K, V, IS> {
+    let mut result = replacement;
 
-                if left.is_leaf() && right.col == Black {
-                    return right.clone().inc();
-                }
+    for &(parent, went_left) in path.iter().rev() {
+        let parent_data = parent.get_data();
+        result = if went_left {
+            bubble(parent.col, result, parent_data.item.clone(), parent_data.right.clone())
+        } else {
+            bubble(parent.col, parent_data.left.clone(), parent_data.item.clone(), result)
+        };
+    }
 
-                if left.col == Black && right.is_leaf() {
-                    return left.clone().inc();
-                }
-            }
+    result
+}
 
-            if !left.is_leaf() && !right.is_leaf() {
-                let kvp = left.find_max_kvp();
-                return bubble(node.col,
-                              remove_max(left),
-                              kvp.clone(),
-                              right.clone());
-            }
+// Same as `remove()` but removes the max node of the left subtree without recursion:
+fn remove_iterative<K: Ord+Clone+Send+Sync, V: Clone+Send+Sync, IS: ItemStore<K, V>>(
+    node: &NodeRef<K, V, IS>) -> NodeRef<K, V, IS> {
+    assert!(!node.is_leaf());
+    let node_data = node.get_data();
 
-            unreachable!();
-        }
+    if node_data.left.is_leaf() || node_data.right.is_leaf() {
+        return remove(node);
+    }
 
-        // Kills a double-black, or moves it to the top:
-        fn bubble<K: Ord+Clone+Send+Sync,
-                  V: Clone+Send+Sync,
-                  IS: ItemStore<K, V>>(
-                    color: Color,
-                    l: NodeRef<K, V, IS>,
-                    kvp: IS,
-                    r: NodeRef<K, V, IS>)
-                 -> NodeRef<K, V, IS> {
-            if l.col == DoubleBlack || r.col == DoubleBlack {
-                new_node(color.inc(), l.dec(), kvp, r.dec()).balance()
-            } else {
-                new_node(color, l, kvp, r)
-            }
-        }
+    let mut path: Vec<(&NodeRef<K, V, IS>, bool)> = Vec::new();
+    let mut max_node = &node_data.left;
+    while !max_node.get_data().right.is_leaf() {
+        path.push((max_node, false));
+        max_node = &max_node.get_data().right;
+    }
 
-        // Removes the max node:
-        fn remove_max<K: Ord+Clone+Send+Sync,
-                      V: Clone+Send+Sync,
-                      IS: ItemStore<K, V>>(
-                        node: &NodeRef<K, V, IS>)
-                     -> NodeRef<K, V, IS> {
-            assert!(!node.is_leaf());
-            let node_data = node.get_data();
-            if node_data.right.is_leaf() {
-                remove(node)
-            } else {
-                bubble(node.col,
-                       node_data.left.clone(),
-                       node_data.item.clone(),
-                       remove_max(&node_data.right))
-            }
-        }
+    bubble(node.col,
+           rebuild_path(path.as_slice(), remove(max_node)),
+           max_node.get_data().item.clone(),
+           node_data.right.clone())
+}
 
-        // Delete the key, and color the new root black
-        del(self, search_key, removal_count).blacken()
+// Removes this node. might leave behind a double-black node:
+fn remove<K: Ord+Clone+Send+Sync, V: Clone+Send+Sync, IS: ItemStore<K, V>>(
+    node: &NodeRef<K, V, IS>) -> NodeRef<K, V, IS> {
+    assert!(!node.is_leaf());
+
+    let node_data = node.get_data();
+    let left = &node_data.left;
+    let right = &node_data.right;
+
+    if left.is_leaf() && right.is_leaf() {
+        return if node.col == Red {
+            new_leaf(Black)
+        } else {
+            assert!(node.col == Black);
+            new_leaf(DoubleBlack)
+        };
     }
-}
 
-struct RedBlackTree<K, V, IS> {
-    root: NodeRef<K, V, IS>,
-    len: uint,
-}
+    if node.col == Red {
+        if right.is_leaf() {
+            return left.clone();
+        }
 
-impl<K, V, IS: ItemStore<K, V>> Clone for RedBlackTree<K, V, IS> {
-    fn clone(&self) -> RedBlackTree<K, V, IS> {
-        RedBlackTree {
-            root: self.root.clone(),
-            len: self.len
+        if left.is_leaf() {
+            return right.clone();
         }
     }
-}
 
-impl<K: Ord+Clone+Send+Sync, V: Clone+Send+Sync, IS: ItemStore<K, V>> RedBlackTree<K, V, IS> {
-    pub fn new() -> RedBlackTree<K, V, IS> {
-        RedBlackTree {
-            root: new_leaf(Black),
-            len: 0,
+    if node.col == Black {
+        if left.col == Red && right.is_leaf() {
+            let left_child_data = left.get_data();
+            return new_node(Black,
+                            left_child_data.left.clone(),
+                            left_child_data.item.clone(),
+                            left_child_data.right.clone());
         }
-    }
 
-    pub fn find<'a>(&'a self, search_key: &K) -> Option<&'a V> {
-        self.root.find(search_key)
-    }
+        if left.is_leaf() && right.col == Red {
+            let right_child_data = right.get_data();
+            return new_node(Black,
+                            right_child_data.left.clone(),
+                            right_child_data.item.clone(),
+                            right_child_data.right.clone());
+        }
 
-    pub fn insert(self, kvp: IS) -> (RedBlackTree<K, V, IS>, bool) {
-        let mut insertion_count = 0xdeadbeaf;
-        let new_root = self.root.modify_at(kvp, &mut insertion_count);
-        assert!(insertion_count != 0xdeadbeaf);
-        (RedBlackTree { root: new_root, len: self.len + insertion_count }, insertion_count != 0)
-    }
+        if left.is_leaf() && right.col == Black {
+            return right.clone().inc();
+        }
 
-    pub fn remove(self, key: &K) -> (RedBlackTree<K, V, IS>, bool) {
-        let mut removal_count = 0xdeadbeaf;
-        let new_root = self.root.delete(key, &mut removal_count);
-        assert!(removal_count != 0xdeadbeaf);
-        (RedBlackTree { root: new_root, len: self.len - removal_count }, removal_count != 0)
+        if left.col == Black && right.is_leaf() {
+            return left.clone().inc();
+        }
     }
 
-    // fn balanced(&self) -> bool {
-    //     self.root.black_balanced()
-    // }
+    if !left.is_leaf() && !right.is_leaf() {
+        let kvp = left.find_max_kvp();
+        return bubble(node.col,
+                      remove_max(left),
+                      kvp.clone(),
+                      right.clone());
+    }
 
-    // fn no_red_red(&self) -> bool {
-    //     self.root.no_red_red()
-    // }
+    unreachable!();
 }
 
-impl<K: Hash+Eq+Send+Sync+Ord+Clone, V: Send+Sync+Clone> PersistentMap<K, V> for RedBlackTree<K, V, CopyStore<K, V>> {
-    #[inline]
-    fn insert(self, key: K, value: V) -> (RedBlackTree<K, V, CopyStore<K, V>>, bool) {
-        self.insert(CopyStore::new(key, value))
-    }
-
-    #[inline]
-    fn remove(self, key: &K) -> (RedBlackTree<K, V, CopyStore<K, V>>, bool) {
-        self.remove(key)
+// Kills a double-black, or moves it to the top:
+fn bubble<K: Ord+Clone+Send+Sync,
+          V: Clone+Send+Sync,
+          IS: ItemStore<K, V>>(
+            color: Color,
+            l: NodeRef<K, V, IS>,
+            kvp: IS,
+            r: NodeRef<K, V, IS>)
+         -> NodeRef<K, V, IS> {
+    if l.col == DoubleBlack || r.col == DoubleBlack {
+        new_node(color.inc(), l.dec(), kvp, r.dec()).balance()
+    } else {
+        new_node(color, l, kvp, r)
     }
 }
 
-impl<K: Hash+Eq+Send+Sync+Ord+Clone, V: Send+Sync+Clone> PersistentMap<K, V> for RedBlackTree<K, V, ShareStore<K, V>> {
-    #[inline]
-    fn insert(self, key: K, value: V) -> (RedBlackTree<K, V, ShareStore<K, V>>, bool) {
-        self.insert(ShareStore::new(key, value))
+// Removes the max node:
+fn remove_max<K: Ord+Clone+Send+Sync,
+              V: Clone+Send+Sync,
+              IS: ItemStore<K, V>>(
+                node: &NodeRef<K, V, IS>)
+             -> NodeRef<K, V, IS> {
+    assert!(!node.is_leaf());
+    let node_data = node.get_data();
+    if node_data.right.is_leaf() {
+        remove(node)
+    } else {
+        bubble(node.col,
+               node_data.left.clone(),
+               node_data.item.clone(),
+               remove_max(&node_data.right))
     }
+}
 
-    #[inline]
-    fn remove(self, key: &K) -> (RedBlackTree<K, V, ShareStore<K, V>>, bool) {
-        self.remove(key)
+// Removes the node with the smallest key from the subtree rooted at `node`. Returns the new
+// subtree, which might have a double-black root, together with the removed item:
+fn remove_min<'a,
+              K: Ord+Clone+Send+Sync,
+              V: Clone+Send+Sync,
+              IS: ItemStore<K, V>>(
+                node: &'a NodeRef<K, V, IS>)
+             -> (NodeRef<K, V, IS>, &'a IS) {
+    assert!(!node.is_leaf());
+    let node_data = node.get_data();
+    if node_data.left.is_leaf() {
+        (remove(node), &node_data.item)
+    } else {
+        let (new_left, min_item) = remove_min(&node_data.left);
+        (bubble(node.col, new_left, node_data.item.clone(), node_data.right.clone()), min_item)
+    }
+}
+
+// Joins `left`, `item`, and `right` into one balanced tree. All keys in `left` must be smaller and
+// all keys in `right` must be greater than the key of `item`. Runs in time proportional to the
+// difference of the black heights of `left` and `right`.
+fn join<K: Ord+Clone+Send+Sync,
+        V: Clone+Send+Sync,
+        IS: ItemStore<K, V>>(
+            left: NodeRef<K, V, IS>,
+            item: IS,
+            right: NodeRef<K, V, IS>)
+         -> NodeRef<K, V, IS> {
+    // Joins `right` into the right spine of the higher tree `left`:
+    fn join_right<K: Ord+Clone+Send+Sync, V: Clone+Send+Sync, IS: ItemStore<K, V>>(
+        left: &NodeRef<K, V, IS>,
+        left_height: uint,
+        item: IS,
+        right: NodeRef<K, V, IS>,
+        right_height: uint)
+     -> NodeRef<K, V, IS> {
+        if left.col == Black && left_height == right_height {
+            return new_node(Red, left.clone(), item, right);
+        }
+
+        let left_data = left.get_data();
+        let child_height = if left.col == Black { left_height - 1 } else { left_height };
+        let new_right = join_right(&left_data.right, child_height, item, right, right_height);
+
+        if left.col == Black && new_right.col == Red && new_right.get_data().right.col == Red {
+            // Red-red violation below a black node: rotate left
+            let new_right_data = new_right.get_data();
+            return new_node(Red,
+                            new_node(Black,
+                                     left_data.left.clone(),
+                                     left_data.item.clone(),
+                                     new_right_data.left.clone()),
+                            new_right_data.item.clone(),
+                            new_right_data.right.clone().blacken());
+        }
+
+        new_node(left.col, left_data.left.clone(), left_data.item.clone(), new_right)
+    }
+
+    // Joins `left` into the left spine of the higher tree `right`:
+    fn join_left<K: Ord+Clone+Send+Sync, V: Clone+Send+Sync, IS: ItemStore<K, V>>(
+        left: NodeRef<K, V, IS>,
+        left_height: uint,
+        item: IS,
+        right: &NodeRef<K, V, IS>,
+        right_height: uint)
+     -> NodeRef<K, V, IS> {
+        if right.col == Black && left_height == right_height {
+            return new_node(Red, left, item, right.clone());
+        }
+
+        let right_data = right.get_data();
+        let child_height = if right.col == Black { right_height - 1 } else { right_height };
+        let new_left = join_left(left, left_height, item, &right_data.left, child_height);
+
+        if right.col == Black && new_left.col == Red && new_left.get_data().left.col == Red {
+            // Red-red violation below a black node: rotate right
+            let new_left_data = new_left.get_data();
+            return new_node(Red,
+                            new_left_data.left.clone().blacken(),
+                            new_left_data.item.clone(),
+                            new_node(Black,
+                                     new_left_data.right.clone(),
+                                     right_data.item.clone(),
+                                     right_data.right.clone()));
+        }
+
+        new_node(right.col, new_left, right_data.item.clone(), right_data.right.clone())
+    }
+
+    debug_assert!(left.is_leaf() || *left.find_max_kvp().key() < *item.key());
+    debug_assert!(right.is_leaf() || *right.find_min_kvp().key() > *item.key());
+
+    let left = left.blacken();
+    let right = right.blacken();
+    let left_height = left.black_height();
+    let right_height = right.black_height();
+
+    if left_height > right_height {
+        join_right(&left, left_height, item, right, right_height).blacken()
+    } else if left_height < right_height {
+        join_left(left, left_height, item, &right, right_height).blacken()
+    } else {
+        new_node(Black, left, item, right)
+    }
+}
+
+// Builds a balanced tree from the next `count` items of `items`, which must be sorted by strictly
+// increasing key. All levels but the deepest are complete and black; the nodes on the deepest level
+// (if it is incomplete) are red.
+fn build_sorted<K: Ord+Clone+Send+Sync,
+                V: Clone+Send+Sync,
+                IS: ItemStore<K, V>,
+                I: Iterator<IS>>(
+                    items: &mut I,
+                    count: uint)
+                 -> NodeRef<K, V, IS> {
+    fn build<K: Ord+Clone+Send+Sync, V: Clone+Send+Sync, IS: ItemStore<K, V>, I: Iterator<IS>>(
+        items: &mut I,
+        count: uint,
+        depth: uint,
+        red_depth: uint)
+     -> NodeRef<K, V, IS> {
+        if count == 0 {
+            return new_leaf(Black);
+        }
+
+        let left_count = count / 2;
+        let left = build(items, left_count, depth + 1, red_depth);
+        let item = items.next().expect("Not enough items to build tree");
+        let right = build(items, count - left_count - 1, depth + 1, red_depth);
+        new_node(if depth == red_depth { Red } else { Black }, left, item, right)
+    }
+
+    // The depth of the first level that is not complete
+    build(items, count, 0, max_black_height_for(count))
+}
+
+// A change to a single key, as applied by `RedBlackTree::apply_delta()`
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub enum Delta<V> {
+    // Sets the key to the given value
+    Set(V),
+    // Removes the key
+    Remove,
+}
+
+// Describes how much memory a sequence of tree versions saves by sharing nodes. See
+// `RedBlackTree::sharing_report()`.
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub struct SharingReport {
+    // The number of nodes actually allocated for all versions together
+    pub distinct_nodes: uint,
+    // The number of nodes needed if every version were stored as an independent copy
+    pub logical_nodes: uint,
+}
+
+impl SharingReport {
+    // How many logical nodes there are per allocated node. Larger is better.
+    pub fn sharing_ratio(&self) -> f64 {
+        if self.distinct_nodes == 0 {
+            1.0
+        } else {
+            self.logical_nodes as f64 / self.distinct_nodes as f64
+        }
+    }
+}
+
+// The largest black height a red-black tree with `len` entries can have. A tree of black height
+// `h` has at least `2^h - 1` entries.
+pub fn max_black_height_for(len: uint) -> uint {
+    let mut height = 0u;
+    while (1u << (height + 1)) - 1 <= len {
+        height += 1;
+    }
+    height
+}
+
+// A value that is either borrowed from a tree or owned. Allows code that only sometimes needs to
+// own a value to clone it only when it has to.
+pub enum Cow<'a, V> {
+    Borrowed(&'a V),
+    Owned(V),
+}
+
+impl<'a, V: Clone> Cow<'a, V> {
+    pub fn is_borrowed(&self) -> bool {
+        match *self {
+            Borrowed(_) => true,
+            Owned(_) => false
+        }
+    }
+
+    // Returns the owned value, cloning it if it is borrowed
+    pub fn into_owned(self) -> V {
+        match self {
+            Borrowed(value) => value.clone(),
+            Owned(value) => value
+        }
+    }
+}
+
+impl<'a, V> Deref<V> for Cow<'a, V> {
+    fn deref<'b>(&'b self) -> &'b V {
+        match *self {
+            Borrowed(value) => value,
+            Owned(ref value) => value
+        }
+    }
+}
+
+// A key that is ordered by a comparison function instead of its own `Ord` implementation. A tree
+// keyed by `CmpKey`s uses that function for all of its operations, so all keys in a tree must
+// carry the same function.
+pub struct CmpKey<K> {
+    pub key: K,
+    pub cmp: fn(&K, &K) -> Ordering,
+}
+
+impl<K> CmpKey<K> {
+    pub fn new(key: K, cmp: fn(&K, &K) -> Ordering) -> CmpKey<K> {
+        CmpKey {
+            key: key,
+            cmp: cmp
+        }
+    }
+}
+
+impl<K: Clone> Clone for CmpKey<K> {
+    fn clone(&self) -> CmpKey<K> {
+        CmpKey {
+            key: self.key.clone(),
+            cmp: self.cmp
+        }
+    }
+}
+
+impl<K> Ord for CmpKey<K> {
+    fn cmp(&self, other: &CmpKey<K>) -> Ordering {
+        (self.cmp)(&self.key, &other.key)
+    }
+}
+
+impl_cmp_traits!([K] CmpKey<K>)
+
+// A key ordered by a primary comparison function, with ties broken by a secondary one. Allows for
+// storing entries whose primary keys are equal, e.g. events keyed by timestamp that are told apart
+// by a sequence number, in a deterministic order.
+pub struct TieBreakKey<K> {
+    pub key: K,
+    pub primary: fn(&K, &K) -> Ordering,
+    pub tie_break: fn(&K, &K) -> Ordering,
+}
+
+impl<K> TieBreakKey<K> {
+    pub fn new(key: K,
+               primary: fn(&K, &K) -> Ordering,
+               tie_break: fn(&K, &K) -> Ordering)
+            -> TieBreakKey<K> {
+        TieBreakKey {
+            key: key,
+            primary: primary,
+            tie_break: tie_break
+        }
+    }
+}
+
+impl<K: Clone> Clone for TieBreakKey<K> {
+    fn clone(&self) -> TieBreakKey<K> {
+        TieBreakKey {
+            key: self.key.clone(),
+            primary: self.primary,
+            tie_break: self.tie_break
+        }
+    }
+}
+
+impl<K> Ord for TieBreakKey<K> {
+    fn cmp(&self, other: &TieBreakKey<K>) -> Ordering {
+        match (self.primary)(&self.key, &other.key) {
+            Equal => (self.tie_break)(&self.key, &other.key),
+            ordering => ordering
+        }
+    }
+}
+
+impl_cmp_traits!([K] TieBreakKey<K>)
+
+// Which input of `RedBlackTree::union_tracked()` an entry was taken from
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub enum Source {
+    Left,
+    Right,
+    // The key was contained in both inputs. The value is the left one.
+    Both,
+}
+
+// How `RedBlackTree::map_values_with_policy()` treats nodes whose value does not change
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub enum SharePolicy {
+    // Builds every node anew, so the result shares no memory with the original tree
+    CopyAll,
+    // Keeps subtrees whose values are all unchanged, so they stay shared with the original tree
+    ShareUnchanged,
+}
+
+// Counts the comparisons made between the keys it hands out. Wrapping the keys of a tree with
+// `CmpCounter::key()` shows how many comparisons an operation on that tree costs.
+#[deriving(Clone)]
+pub struct CmpCounter {
+    count: Arc<AtomicUint>,
+}
+
+impl CmpCounter {
+    pub fn new() -> CmpCounter {
+        CmpCounter {
+            count: Arc::new(AtomicUint::new(0))
+        }
+    }
+
+    pub fn key<K: Ord>(&self, key: K) -> CountingCmp<K> {
+        CountingCmp {
+            key: key,
+            count: self.count.clone()
+        }
+    }
+
+    // The number of comparisons made since creation or the last reset
+    pub fn count(&self) -> uint {
+        self.count.load(SeqCst)
+    }
+
+    pub fn reset(&self) {
+        self.count.store(0, SeqCst);
+    }
+}
+
+// A key that adds one to its counter every time it is compared. Works with any `Ord` key,
+// including `CmpKey`.
+pub struct CountingCmp<K> {
+    pub key: K,
+    count: Arc<AtomicUint>,
+}
+
+impl<K: Clone> Clone for CountingCmp<K> {
+    fn clone(&self) -> CountingCmp<K> {
+        CountingCmp {
+            key: self.key.clone(),
+            count: self.count.clone()
+        }
+    }
+}
+
+impl<K: Ord> Ord for CountingCmp<K> {
+    fn cmp(&self, other: &CountingCmp<K>) -> Ordering {
+        self.count.fetch_add(1, SeqCst);
+        self.key.cmp(&other.key)
+    }
+}
+
+impl_cmp_traits!([K: Ord] CountingCmp<K>)
+
+// The differences between two versions of a map, as computed by `RedBlackTree::diff()`. Each list
+// is sorted by key.
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub struct Changes<K, V> {
+    // Entries only contained in the new version
+    pub added: Vec<(K, V)>,
+    // Keys only contained in the old version
+    pub removed: Vec<K>,
+    // Keys contained in both versions, with their value in the new version
+    pub changed: Vec<(K, V)>,
+}
+
+// Errors reported by the fallible RedBlackTree operations
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub enum RbError {
+    // The requested key is not contained in the tree
+    KeyNotFound,
+    // The entries to decode were not sorted by strictly increasing key
+    DecodeError,
+}
+
+struct RedBlackTree<K, V, IS> {
+    root: NodeRef<K, V, IS>,
+    len: uint,
+}
+
+impl<K, V, IS: ItemStore<K, V>> Clone for RedBlackTree<K, V, IS> {
+    fn clone(&self) -> RedBlackTree<K, V, IS> {
+        RedBlackTree {
+            root: self.root.clone(),
+            len: self.len
+        }
+    }
+}
+
+impl<K: Ord+Clone+Send+Sync, V: Clone+Send+Sync, IS: ItemStore<K, V>> RedBlackTree<K, V, IS> {
+    // Creates an empty tree. This does not allocate: the root of an empty tree is a leaf, and only
+    // non-leaf nodes live on the heap. Creating (and cloning) empty trees is thus as cheap as
+    // copying two words.
+    pub fn new() -> RedBlackTree<K, V, IS> {
+        RedBlackTree {
+            root: new_leaf(Black),
+            len: 0,
+        }
+    }
+
+    pub fn find<'a>(&'a self, search_key: &K) -> Option<&'a V> {
+        self.root.find(search_key)
+    }
+
+    // Returns a presence flag for each of the given keys. If `keys` is sorted, all keys are looked
+    // up in a single descent of the tree.
+    pub fn contains_all(&self, keys: &[K]) -> Vec<bool> {
+        if keys.windows(2).all(|pair| pair[0] <= pair[1]) {
+            let mut result = Vec::with_capacity(keys.len());
+            self.root.contains_sorted(keys, &mut result);
+            result
+        } else {
+            keys.iter().map(|key| self.find(key).is_some()).collect()
+        }
+    }
+
+    // Same as `find()` but wraps the value in a `Cow`, so callers can decide later whether they
+    // need to own it
+    pub fn find_cow<'a>(&'a self, search_key: &K) -> Option<Cow<'a, V>> {
+        self.find(search_key).map(|value| Borrowed(value))
+    }
+
+    // Returns the entry with the smallest key
+    pub fn min_key_value<'a>(&'a self) -> Option<(&'a K, &'a V)> {
+        let mut node = &self.root;
+        let mut min = None;
+
+        while !node.is_leaf() {
+            let node_data = node.get_data();
+            min = Some(&node_data.item);
+            node = &node_data.left;
+        }
+
+        min.map(|kvp| (kvp.key(), kvp.val()))
+    }
+
+    // Returns the entry with the largest key
+    pub fn max_key_value<'a>(&'a self) -> Option<(&'a K, &'a V)> {
+        let mut node = &self.root;
+        let mut max = None;
+
+        while !node.is_leaf() {
+            let node_data = node.get_data();
+            max = Some(&node_data.item);
+            node = &node_data.right;
+        }
+
+        max.map(|kvp| (kvp.key(), kvp.val()))
+    }
+
+    // Returns the entry with the largest key less than `key`
+    pub fn predecessor<'a>(&'a self, key: &K) -> Option<(&'a K, &'a V)> {
+        let mut node = &self.root;
+        let mut candidate = None;
+
+        while !node.is_leaf() {
+            let node_data = node.get_data();
+            if *node_data.item.key() < *key {
+                candidate = Some(&node_data.item);
+                node = &node_data.right;
+            } else {
+                node = &node_data.left;
+            }
+        }
+
+        candidate.map(|kvp| (kvp.key(), kvp.val()))
+    }
+
+    // Returns the entry with the largest key not greater than `key`
+    pub fn floor<'a>(&'a self, key: &K) -> Option<(&'a K, &'a V)> {
+        let mut node = &self.root;
+        let mut candidate = None;
+
+        while !node.is_leaf() {
+            let node_data = node.get_data();
+            if *node_data.item.key() <= *key {
+                candidate = Some(&node_data.item);
+                node = &node_data.right;
+            } else {
+                node = &node_data.left;
+            }
+        }
+
+        candidate.map(|kvp| (kvp.key(), kvp.val()))
+    }
+
+    // Returns the entry with the smallest key greater than `key`
+    pub fn successor<'a>(&'a self, key: &K) -> Option<(&'a K, &'a V)> {
+        let mut node = &self.root;
+        let mut candidate = None;
+
+        while !node.is_leaf() {
+            let node_data = node.get_data();
+            if *node_data.item.key() > *key {
+                candidate = Some(&node_data.item);
+                node = &node_data.left;
+            } else {
+                node = &node_data.right;
+            }
+        }
+
+        candidate.map(|kvp| (kvp.key(), kvp.val()))
+    }
+
+    // Returns a tree with every key replaced by `f(key)`. `f` must be strictly increasing, so the
+    // new keys are in the same order as the old ones and the tree keeps its exact shape and colors.
+    pub fn shift_keys(&self, f: |&K| -> K) -> RedBlackTree<K, V, IS> {
+        fn shift_node<K: Ord+Clone+Send+Sync,
+                      V: Clone+Send+Sync,
+                      IS: ItemStore<K, V>>(
+                          node: &NodeRef<K, V, IS>,
+                          f: &mut |&K| -> K,
+                          previous: &mut Option<K>)
+                       -> NodeRef<K, V, IS> {
+            if node.is_leaf() {
+                return node.clone();
+            }
+
+            let node_data = node.get_data();
+            let left = shift_node(&node_data.left, f, previous);
+            let key = (*f)(node_data.item.key());
+            debug_assert!(previous.as_ref().map_or(true, |previous| *previous < key),
+                          "shift_keys() requires a strictly increasing key transform");
+            *previous = Some(key.clone());
+            let right = shift_node(&node_data.right, f, previous);
+
+            new_node(node.col, left, ItemStore::new(key, node_data.item.val().clone()), right)
+        }
+
+        let mut f = f;
+        RedBlackTree {
+            root: shift_node(&self.root, &mut f, &mut None),
+            len: self.len
+        }
+    }
+
+    // Returns the first entry whose key satisfies `pred`. `pred` must be false for all keys below
+    // some threshold and true for all others, which allows for a single descent from the root.
+    pub fn partition_point<'a>(&'a self, pred: |&K| -> bool) -> Option<(&'a K, &'a V)> {
+        let mut node = &self.root;
+        let mut candidate = None;
+
+        while !node.is_leaf() {
+            let node_data = node.get_data();
+            if pred(node_data.item.key()) {
+                candidate = Some(&node_data.item);
+                node = &node_data.left;
+            } else {
+                node = &node_data.right;
+            }
+        }
+
+        candidate.map(|kvp| (kvp.key(), kvp.val()))
+    }
+
+    // Returns the `n`-th entry with a key greater than `key`, counting from 0. `n = 0` yields the
+    // successor of `key`.
+    pub fn nth_successor<'a>(&'a self, key: &K, n: uint) -> Option<(&'a K, &'a V)> {
+        let first_greater = self.len - self.root.count_greater(key);
+        self.select(first_greater + n)
+    }
+
+    // Returns both the predecessor and the successor of `key`, found in a single descent
+    pub fn neighbors<'a>(&'a self, key: &K)
+                         -> (Option<(&'a K, &'a V)>, Option<(&'a K, &'a V)>) {
+        let mut node = &self.root;
+        let mut predecessor = None;
+        let mut successor = None;
+
+        while !node.is_leaf() {
+            let node_data = node.get_data();
+
+            if *key < *node_data.item.key() {
+                successor = Some(&node_data.item);
+                node = &node_data.left;
+            } else if *key > *node_data.item.key() {
+                predecessor = Some(&node_data.item);
+                node = &node_data.right;
+            } else {
+                if !node_data.left.is_leaf() {
+                    predecessor = Some(node_data.left.find_max_kvp());
+                }
+                if !node_data.right.is_leaf() {
+                    successor = Some(node_data.right.find_min_kvp());
+                }
+                break;
+            }
+        }
+
+        (predecessor.map(|kvp| (kvp.key(), kvp.val())),
+         successor.map(|kvp| (kvp.key(), kvp.val())))
+    }
+
+    // Same as `find()` but also returns the number of nodes visited during the lookup
+    pub fn find_with_depth<'a>(&'a self, search_key: &K) -> (Option<&'a V>, uint) {
+        let mut node = &self.root;
+        let mut depth = 0u;
+
+        while !node.is_leaf() {
+            let node_data = node.get_data();
+            depth += 1;
+
+            if *search_key < *node_data.item.key() {
+                node = &node_data.left;
+            } else if *search_key > *node_data.item.key() {
+                node = &node_data.right;
+            } else {
+                return (Some(node_data.item.val()), depth);
+            }
+        }
+
+        (None, depth)
+    }
+
+    // Same as `find()` but also tells whether the key is the minimum and whether it is the maximum
+    // of the tree. A key is the minimum if the lookup never went right and the key has no left
+    // subtree, and vice versa for the maximum.
+    pub fn find_with_extremes<'a>(&'a self, search_key: &K) -> Option<(&'a V, bool, bool)> {
+        let mut node = &self.root;
+        let mut went_left = false;
+        let mut went_right = false;
+
+        while !node.is_leaf() {
+            let node_data = node.get_data();
+
+            if *search_key < *node_data.item.key() {
+                went_left = true;
+                node = &node_data.left;
+            } else if *search_key > *node_data.item.key() {
+                went_right = true;
+                node = &node_data.right;
+            } else {
+                return Some((node_data.item.val(),
+                             !went_right && node_data.left.is_leaf(),
+                             !went_left && node_data.right.is_leaf()));
+            }
+        }
+
+        None
+    }
+
+    // Is there an entry for `key`?
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.root.find(key).is_some()
+    }
+
+    // Same as `find()` but reports a missing key as `Err(KeyNotFound)`
+    pub fn find_or_err<'a>(&'a self, search_key: &K) -> Result<&'a V, RbError> {
+        match self.find(search_key) {
+            Some(value) => Ok(value),
+            None => Err(KeyNotFound)
+        }
+    }
+
+    // Same as `find()` but returns `default` for a missing key
+    pub fn find_or<'a>(&'a self, search_key: &K, default: &'a V) -> &'a V {
+        match self.find(search_key) {
+            Some(value) => value,
+            None => default
+        }
+    }
+
+    pub fn insert(self, kvp: IS) -> (RedBlackTree<K, V, IS>, bool) {
+        self.insert_item(kvp, None, &mut |_, kvp| Some(kvp))
+    }
+
+    // Same as `insert()`, but returns the value previously stored for the key, if any
+    pub fn insert_with_old(self, key: K, value: V) -> (RedBlackTree<K, V, IS>, Option<V>) {
+        let mut replaced = None;
+        let (tree, is_new) = self.insert_item(ItemStore::new(key, value), Some(&mut replaced),
+                                              &mut |_, kvp| Some(kvp));
+        assert!(is_new == replaced.is_none());
+        (tree, replaced.map(|item| item.val().clone()))
+    }
+
+    // Only clones the replaced item into `replaced` if the caller asks for it
+    fn insert_item(self, kvp: IS, replaced: Option<&mut Option<IS>>,
+                   merge: &mut |&IS, IS| -> Option<IS>) -> (RedBlackTree<K, V, IS>, bool) {
+        let mut insertion_count = 0xdeadbeaf;
+        let new_root = self.root.modify_at(kvp, &mut insertion_count, replaced, merge);
+        assert!(insertion_count != 0xdeadbeaf);
+        (RedBlackTree { root: new_root, len: self.len + insertion_count }, insertion_count == 1)
+    }
+
+    // Returns a new version with `key` mapped to `value`, leaving this one in place. Only clones
+    // the root handle, so both versions share all nodes off the insertion path.
+    pub fn with_inserted(&self, key: K, value: V) -> RedBlackTree<K, V, IS> {
+        self.clone().insert(ItemStore::new(key, value)).val0()
+    }
+
+    // Same as `insert()`, but if the tree already contains an equal key, the stored key is kept and
+    // only the value is replaced. This allows for sharing key instances between versions.
+    pub fn insert_interned(self, key: K, value: V) -> (RedBlackTree<K, V, IS>, bool) {
+        self.insert_item(ItemStore::new(key, value), None, &mut |stored, kvp| {
+            Some(ItemStore::new(stored.key().clone(), kvp.val().clone()))
+        })
+    }
+
+    pub fn remove(self, key: &K) -> (RedBlackTree<K, V, IS>, bool) {
+        self.remove_item(key, None)
+    }
+
+    // Same as `remove()`, but returns the removed value instead of whether there was one
+    pub fn remove_with_value(self, key: &K) -> (RedBlackTree<K, V, IS>, Option<V>) {
+        let mut removed = None;
+        let (tree, was_removed) = self.remove_item(key, Some(&mut removed));
+        assert!(was_removed == removed.is_some());
+        (tree, removed.map(|item| item.val().clone()))
+    }
+
+    // Only clones the removed item into `removed` if the caller asks for it
+    fn remove_item(self, key: &K, removed: Option<&mut Option<IS>>)
+                   -> (RedBlackTree<K, V, IS>, bool) {
+        let mut removal_count = 0xdeadbeaf;
+        let new_root = self.root.delete(key, &mut removal_count, removed);
+        assert!(removal_count != 0xdeadbeaf);
+        // The transient colors used while deleting must never escape
+        assert!(new_root.col == Black);
+        (RedBlackTree { root: new_root, len: self.len - removal_count }, removal_count == 1)
+    }
+
+    // Removes the entry with the smallest key and returns it along with the new tree. An empty
+    // tree is returned unchanged.
+    pub fn remove_min(self) -> (RedBlackTree<K, V, IS>, Option<(K, V)>) {
+        if self.len == 0 {
+            return (self, None);
+        }
+
+        let (new_root, key, val) = {
+            let (new_root, min_item) = remove_min(&self.root);
+            (new_root.blacken(), min_item.key().clone(), min_item.val().clone())
+        };
+        assert!(new_root.col == Black);
+        (RedBlackTree { root: new_root, len: self.len - 1 }, Some((key, val)))
+    }
+
+    // Same as `remove()` but implemented without recursion, so stack usage does not depend on the
+    // height of the tree
+    pub fn remove_iterative(self, key: &K) -> (RedBlackTree<K, V, IS>, bool) {
+        let mut removal_count = 0xdeadbeaf;
+        let new_root = self.root.delete_iterative(key, &mut removal_count);
+        assert!(removal_count != 0xdeadbeaf);
+        assert!(new_root.col == Black);
+        (RedBlackTree { root: new_root, len: self.len - removal_count }, removal_count != 0)
+    }
+
+    fn balanced(&self) -> bool {
+        self.root.black_balanced()
+    }
+
+    // Checks all red-black tree invariants. The root must be plain black, whether it is a leaf
+    // or not, so none of the transient colors used while deleting may be left over.
+    #[cfg(test)]
+    fn check_invariants(&self) -> bool {
+        self.root.col == Black && self.balanced() && self.no_red_red() &&
+        self.len_matches_structure()
+    }
+
+    // Does the `len` field agree with the number of nodes in the tree? Counts the nodes without
+    // relying on the stored subtree sizes.
+    #[cfg(test)]
+    fn len_matches_structure(&self) -> bool {
+        fn count<K, V, IS>(node: &NodeRef<K, V, IS>) -> uint {
+            match node.data {
+                Some(ref node_data) => 1 + count(&node_data.left) + count(&node_data.right),
+                None => 0
+            }
+        }
+
+        count(&self.root) == self.len
+    }
+
+    // The number of red nodes in the tree
+    #[cfg(test)]
+    fn red_node_count(&self) -> uint {
+        self.root.count_color(Red)
+    }
+
+    // The number of black nodes in the tree, not counting leaves
+    #[cfg(test)]
+    fn black_node_count(&self) -> uint {
+        self.root.count_color(Black)
+    }
+
+    // The number of distinct node allocations reachable from the root. Every entry lives in
+    // exactly one node, so for a well-formed tree this equals `len`.
+    #[cfg(test)]
+    fn allocated_node_count(&self) -> uint {
+        RedBlackTree::sharing_report([self.clone()]).distinct_nodes
+    }
+
+    // Does any node of this tree also belong to `other`? Stops at the first shared node found.
+    fn shares_nodes_with(&self, other: &RedBlackTree<K, V, IS>) -> bool {
+        fn collect<K: Ord+Clone+Send+Sync, V: Clone+Send+Sync, IS: ItemStore<K, V>>(
+            node: &NodeRef<K, V, IS>,
+            nodes: &mut HashSet<uint>) {
+            match node.node_ptr() {
+                Some(ptr) => {
+                    nodes.insert(ptr as uint);
+                    let node_data = node.get_data();
+                    collect(&node_data.left, nodes);
+                    collect(&node_data.right, nodes);
+                }
+                None => {}
+            }
+        }
+
+        fn any_shared<K: Ord+Clone+Send+Sync, V: Clone+Send+Sync, IS: ItemStore<K, V>>(
+            node: &NodeRef<K, V, IS>,
+            nodes: &HashSet<uint>) -> bool {
+            match node.node_ptr() {
+                Some(ptr) => {
+                    let node_data = node.get_data();
+                    nodes.contains(&(ptr as uint)) ||
+                    any_shared(&node_data.left, nodes) ||
+                    any_shared(&node_data.right, nodes)
+                }
+                None => false
+            }
+        }
+
+        let mut nodes = HashSet::new();
+        collect(&other.root, &mut nodes);
+        any_shared(&self.root, &nodes)
+    }
+
+    // Do both trees have exactly the same shape and colors? Unlike comparing the entries, this
+    // also tells apart equal maps that were built in different ways.
+    #[cfg(test)]
+    fn structurally_eq(&self, other: &RedBlackTree<K, V, IS>) -> bool {
+        self.len == other.len && self.root.structurally_eq(&other.root)
+    }
+
+    fn no_red_red(&self) -> bool {
+        self.root.no_red_red()
+    }
+}
+
+impl<K: Ord+Clone+Send+Sync, V: Clone+Send+Sync, IS: ItemStore<K, V>> RedBlackTree<K, V, IS> {
+    // The number of black nodes on each path from the root to a leaf (not counting the leaf)
+    pub fn black_height(&self) -> uint {
+        self.root.black_height()
+    }
+
+    // The number of nodes on the longest path from the root to a leaf (not counting the leaf).
+    // Never more than 2 * log2(len + 1).
+    pub fn height(&self) -> uint {
+        self.root.height()
+    }
+
+    // Wraps a balanced subtree with a black root
+    fn from_root(root: NodeRef<K, V, IS>) -> RedBlackTree<K, V, IS> {
+        assert!(root.col == Black);
+        RedBlackTree {
+            len: root.size(),
+            root: root
+        }
+    }
+
+    // Splits the tree into one with the first `index` entries in key order and one with the rest
+    pub fn split_at_rank(self, index: uint) -> (RedBlackTree<K, V, IS>, RedBlackTree<K, V, IS>) {
+        let (left, right) = self.root.split_at_rank(index);
+        (RedBlackTree::from_root(left.blacken()), RedBlackTree::from_root(right.blacken()))
+    }
+
+    // Removes the entries at positions `[start, end)` in key order and returns them along with
+    // the remaining tree. Takes O(log n + k) time for k removed entries.
+    pub fn remove_rank_range(self, start: uint, end: uint)
+                             -> (RedBlackTree<K, V, IS>, Vec<(K, V)>) {
+        assert!(start <= end);
+        let (before, rest) = self.split_at_rank(start);
+        let (removed, after) = rest.split_at_rank(end - start);
+        let removed_entries = removed.iter().map(|(k, v)| (k.clone(), v.clone())).collect();
+        (RedBlackTree::concat(before, after), removed_entries)
+    }
+
+    // Joins two trees and a middle item into one balanced tree. All keys of `left` must be smaller
+    // and all keys of `right` must be greater than the key of `kvp`.
+    pub fn join(left: RedBlackTree<K, V, IS>,
+                kvp: IS,
+                right: RedBlackTree<K, V, IS>)
+             -> RedBlackTree<K, V, IS> {
+        RedBlackTree {
+            root: join(left.root, kvp, right.root),
+            len: left.len + 1 + right.len
+        }
+    }
+
+    // Concatenates two trees. All keys of `left` must be smaller than all keys of `right`.
+    pub fn concat(left: RedBlackTree<K, V, IS>,
+                  right: RedBlackTree<K, V, IS>)
+               -> RedBlackTree<K, V, IS> {
+        if right.len == 0 {
+            return left;
+        }
+
+        if left.len == 0 {
+            return right;
+        }
+
+        let min_kvp = right.root.find_min_kvp().clone();
+        let (right, _) = right.remove(min_kvp.key());
+        RedBlackTree::join(left, min_kvp, right)
+    }
+
+    // Returns a tree containing the entries of both trees. For keys contained in both trees the
+    // value from `self` is kept.
+    pub fn union(self, other: RedBlackTree<K, V, IS>) -> RedBlackTree<K, V, IS> {
+        if self.len >= other.len {
+            let mut result = self;
+            let mut entries = other.iter();
+            loop {
+                match entries.next_item() {
+                    Some(item) => if result.find(item.key()).is_none() {
+                        result = result.insert(item.clone()).val0();
+                    },
+                    None => break
+                }
+            }
+            result
+        } else {
+            let mut result = other;
+            let mut entries = self.iter();
+            loop {
+                match entries.next_item() {
+                    Some(item) => result = result.insert(item.clone()).val0(),
+                    None => break
+                }
+            }
+            result
+        }
+    }
+
+    // Returns a tree containing the entries of both trees. For keys contained in both trees the
+    // value is computed by `on_conflict` from the key, the value in `self`, and the value in
+    // `other`. Runs in a single pass over both trees.
+    pub fn merge_with(self,
+                      other: RedBlackTree<K, V, IS>,
+                      on_conflict: |&K, &V, &V| -> V)
+                   -> RedBlackTree<K, V, IS> {
+        let items = self.merge_items(&other, |left, right| {
+            let val = on_conflict(left.key(), left.val(), right.val());
+            Some(ItemStore::new(left.key().clone(), val))
+        });
+        RedBlackTree::from_sorted(items)
+    }
+
+    // Returns the entries contained in exactly one of the two trees. Runs in a single pass over
+    // both trees.
+    pub fn symmetric_difference(self, other: RedBlackTree<K, V, IS>) -> RedBlackTree<K, V, IS> {
+        RedBlackTree::from_sorted(self.merge_items(&other, |_, _| None))
+    }
+
+    // Iterates over all keys contained in either tree, in ascending order, together with the
+    // value from each tree that contains the key
+    pub fn zip_all<'a, W: Clone+Send+Sync, WIS: ItemStore<K, W>>(
+                   &'a self,
+                   other: &'a RedBlackTree<K, W, WIS>)
+                -> ZipAll<'a, K, V, W, IS, WIS> {
+        ZipAll { items: self.zip_items(other) }
+    }
+
+    // Same as `zip_all()`, but yields the stored items, so that callers can reuse them
+    fn zip_items<'a, W: Clone+Send+Sync, WIS: ItemStore<K, W>>(
+                 &'a self,
+                 other: &'a RedBlackTree<K, W, WIS>)
+              -> ZipItems<'a, K, V, W, IS, WIS> {
+        let mut left = self.iter();
+        let mut right = other.iter();
+        let next_left = left.next_item();
+        let next_right = right.next_item();
+
+        ZipItems {
+            left: left,
+            right: right,
+            next_left: next_left,
+            next_right: next_right
+        }
+    }
+
+    // Returns the number of keys contained in both trees, without building their intersection
+    pub fn intersection_count(&self, other: &RedBlackTree<K, V, IS>) -> uint {
+        self.zip_all(other).filter(|&(_, left, right)| left.is_some() && right.is_some()).count()
+    }
+
+    // Same as `union()`, but each value is tagged with the tree it was taken from
+    pub fn union_tracked<TIS: ItemStore<K, (V, Source)>>(self, other: RedBlackTree<K, V, IS>)
+                                                      -> RedBlackTree<K, (V, Source), TIS> {
+        let mut items: Vec<TIS> = Vec::with_capacity(self.len + other.len);
+
+        for (key, left, right) in self.zip_all(&other) {
+            let (val, source) = match (left, right) {
+                (Some(val), Some(_)) => (val, Both),
+                (Some(val), None) => (val, Left),
+                (None, Some(val)) => (val, Right),
+                (None, None) => unreachable!()
+            };
+
+            items.push(ItemStore::new(key.clone(), (val.clone(), source)));
+        }
+
+        RedBlackTree::from_sorted(items)
+    }
+
+    // Walks both trees in key order, collecting the items whose key is only contained in one of
+    // them. For keys contained in both, `on_both` decides which item, if any, to collect.
+    fn merge_items(&self,
+                   other: &RedBlackTree<K, V, IS>,
+                   on_both: |&IS, &IS| -> Option<IS>)
+                -> Vec<IS> {
+        let mut items: Vec<IS> = Vec::with_capacity(self.len + other.len);
+
+        for pair in self.zip_items(other) {
+            match pair {
+                (Some(left), Some(right)) => {
+                    match on_both(left, right) {
+                        Some(item) => items.push(item),
+                        None => {}
+                    }
+                }
+                (Some(item), None) | (None, Some(item)) => items.push(item.clone()),
+                (None, None) => unreachable!()
+            }
+        }
+
+        items
+    }
+
+    // Same as `merge_with()`, but replaces `self` with the result
+    pub fn merge_in_place(&mut self,
+                          other: RedBlackTree<K, V, IS>,
+                          on_conflict: |&K, &V, &V| -> V) {
+        let this = mem::replace(self, RedBlackTree::new());
+        *self = this.merge_with(other, on_conflict);
+    }
+
+    // Merges any number of trees into one. If the key ranges of the trees are disjoint and
+    // ascending in the order given, the trees are concatenated, which is much cheaper than the
+    // general case. Otherwise they are combined with `union()`, so for duplicate keys the value
+    // from the earliest tree wins.
+    pub fn from_trees(trees: Vec<RedBlackTree<K, V, IS>>) -> RedBlackTree<K, V, IS> {
+        let ordered = {
+            let non_empty: Vec<&RedBlackTree<K, V, IS>> =
+                trees.iter().filter(|tree| tree.len > 0).collect();
+            non_empty.windows(2).all(|pair| {
+                *pair[0].root.find_max_kvp().key() < *pair[1].root.find_min_kvp().key()
+            })
+        };
+
+        let mut result = RedBlackTree::new();
+        for tree in trees.move_iter() {
+            result = if ordered {
+                RedBlackTree::concat(result, tree)
+            } else {
+                result.union(tree)
+            };
+        }
+        result
+    }
+
+    // Builds a tree from items sorted by strictly increasing key in O(n). The result is as balanced
+    // as possible.
+    pub fn from_sorted(items: Vec<IS>) -> RedBlackTree<K, V, IS> {
+        debug_assert!(items.windows(2).all(|pair| *pair[0].key() < *pair[1].key()));
+        let len = items.len();
+        RedBlackTree {
+            root: build_sorted(&mut items.move_iter(), len),
+            len: len
+        }
+    }
+
+    // Builds a tree from entries in ascending key order that may contain runs of equal keys. The
+    // values of each run are folded into the first one with `combine`, so every distinct key ends
+    // up in a single node. Takes a single linear pass.
+    pub fn from_sorted_dedup<I: Iterator<(K, V)>>(entries: I, combine: |&mut V, V|)
+                                                   -> RedBlackTree<K, V, IS> {
+        let mut builder = SortedBuilder::new();
+        let mut pending: Option<(K, V)> = None;
+
+        for (key, val) in entries {
+            pending = match pending {
+                Some((pending_key, mut pending_val)) => {
+                    // Keys are identified by `Ord`, like everywhere else in the tree
+                    if pending_key.cmp(&key) == Equal {
+                        combine(&mut pending_val, val);
+                        Some((pending_key, pending_val))
+                    } else {
+                        builder.push(pending_key, pending_val);
+                        Some((key, val))
+                    }
+                }
+                None => Some((key, val))
+            };
+        }
+
+        match pending {
+            Some((key, val)) => builder.push(key, val),
+            None => {}
+        }
+
+        builder.finish()
+    }
+
+    // Builds a tree in O(n) from entries sorted by strictly increasing key. Returns
+    // `Err(DecodeError)` if a key is not greater than its predecessor.
+    pub fn decode_entries_checked<I: Iterator<(K, V)>>(entries: I)
+                                                        -> Result<RedBlackTree<K, V, IS>, RbError> {
+        let mut items: Vec<IS> = Vec::new();
+
+        for (key, val) in entries {
+            match items.last() {
+                Some(last) if *last.key() >= key => return Err(DecodeError),
+                _ => {}
+            }
+            items.push(ItemStore::new(key, val));
+        }
+
+        Ok(RedBlackTree::from_sorted(items))
+    }
+
+    // Builds a tree in O(n) from entries sorted by strictly increasing key, without verifying the
+    // order. Unsorted input results in a corrupt tree.
+    pub fn decode_entries_unchecked<I: Iterator<(K, V)>>(entries: I) -> RedBlackTree<K, V, IS> {
+        RedBlackTree::from_sorted(entries.map(|(key, val)| ItemStore::new(key, val)).collect())
+    }
+
+    // Builds a tree that indexes `items` by the key `key_fn` projects out of each of them. If two
+    // items map to the same key, the later one wins.
+    pub fn by_key<I: Iterator<V>>(items: I, key_fn: |&V| -> K) -> RedBlackTree<K, V, IS> {
+        let mut tree = RedBlackTree::new();
+        for item in items {
+            let key = key_fn(&item);
+            tree = tree.insert(ItemStore::new(key, item)).val0();
+        }
+        tree
+    }
+
+    // Iterates over all key-value pairs in ascending key order
+    pub fn iter<'a>(&'a self) -> Entries<'a, K, V, IS> {
+        Entries::new(self)
+    }
+
+    // Applies a set of changes to this tree in a single pass over both trees. Keys mapped to
+    // `Set(value)` in `delta` are inserted or overwritten, keys mapped to `Remove` are removed.
+    pub fn apply_delta<DS: ItemStore<K, Delta<V>>>(self,
+                                                   delta: &RedBlackTree<K, Delta<V>, DS>)
+                                                -> RedBlackTree<K, V, IS> {
+        let mut items: Vec<IS> = Vec::with_capacity(self.len + delta.len);
+        let mut base_entries = self.iter();
+        let mut delta_entries = delta.iter();
+        let mut next_base = base_entries.next_item();
+        let mut next_delta = delta_entries.next();
+
+        loop {
+            match (next_base, next_delta) {
+                (None, None) => break,
+                (Some(item), None) => {
+                    items.push(item.clone());
+                    next_base = base_entries.next_item();
+                }
+                (Some(item), Some((key, _))) if *item.key() < *key => {
+                    items.push(item.clone());
+                    next_base = base_entries.next_item();
+                }
+                (_, Some((key, change))) => {
+                    match next_base {
+                        Some(item) if *item.key() == *key => {
+                            next_base = base_entries.next_item();
+                        }
+                        _ => {}
+                    }
+
+                    match *change {
+                        Set(ref val) => items.push(ItemStore::new(key.clone(), val.clone())),
+                        Remove => {}
+                    }
+
+                    next_delta = delta_entries.next();
+                }
+            }
+        }
+
+        RedBlackTree::from_sorted(items)
+    }
+
+    // Applies a changeset as computed by `diff()`. Applying `old.diff(&new)` to `old` reproduces
+    // `new`.
+    pub fn apply_changes(self, changes: &Changes<K, V>) -> RedBlackTree<K, V, IS> {
+        let mut entries: Vec<(K, Delta<V>)> = Vec::with_capacity(changes.added.len() +
+                                                                 changes.removed.len() +
+                                                                 changes.changed.len());
+        for &(ref key, ref val) in changes.added.iter().chain(changes.changed.iter()) {
+            entries.push((key.clone(), Set(val.clone())));
+        }
+        for key in changes.removed.iter() {
+            entries.push((key.clone(), Remove));
+        }
+        entries.sort_by(|a, b| a.ref0().cmp(b.ref0()));
+
+        let delta = RedBlackTree::<K, Delta<V>, CopyStore<K, Delta<V>>>::decode_entries_unchecked(
+            entries.move_iter());
+        self.apply_delta(&delta)
+    }
+
+    // Removes all entries whose value matches `pred`. Returns the remaining tree and the number of
+    // removed entries. The result is built from scratch in a single pass.
+    pub fn remove_by_value(self, pred: |&V| -> bool) -> (RedBlackTree<K, V, IS>, uint) {
+        let mut items: Vec<IS> = Vec::with_capacity(self.len);
+        let mut entries = self.iter();
+
+        loop {
+            match entries.next_item() {
+                Some(item) => if !pred(item.val()) { items.push(item.clone()) },
+                None => break
+            }
+        }
+
+        let removed = self.len - items.len();
+        (RedBlackTree::from_sorted(items), removed)
+    }
+
+    // Keeps only the entries matching `pred`. Returns the remaining tree and the number of dropped
+    // entries. Like `remove_by_value()`, the result is built from scratch in a single pass.
+    pub fn retain_count(self, pred: |&K, &V| -> bool) -> (RedBlackTree<K, V, IS>, uint) {
+        let mut items: Vec<IS> = Vec::with_capacity(self.len);
+        let mut entries = self.iter();
+
+        loop {
+            match entries.next_item() {
+                Some(item) => if pred(item.key(), item.val()) { items.push(item.clone()) },
+                None => break
+            }
+        }
+
+        let dropped = self.len - items.len();
+        (RedBlackTree::from_sorted(items), dropped)
+    }
+
+    // Keeps only the entries matching `pred`
+    pub fn retain(self, pred: |&K, &V| -> bool) -> RedBlackTree<K, V, IS> {
+        self.retain_count(pred).val0()
+    }
+
+    // Do this tree and `ancestor` share any nodes? This is the case if one was derived from the
+    // other, or both from a common version, by persistent operations. Equal trees built
+    // independently share nothing.
+    pub fn is_derived_from(&self, ancestor: &RedBlackTree<K, V, IS>) -> bool {
+        self.shares_nodes_with(ancestor)
+    }
+
+    // Reports how many nodes are shared between the given versions of a tree
+    pub fn sharing_report(versions: &[RedBlackTree<K, V, IS>]) -> SharingReport {
+        // Adds the nodes of a subtree to `seen`, skipping subtrees that have been seen before
+        fn collect<K: Ord+Clone+Send+Sync, V: Clone+Send+Sync, IS: ItemStore<K, V>>(
+            node: &NodeRef<K, V, IS>,
+            seen: &mut HashSet<uint>) {
+            match node.node_ptr() {
+                Some(ptr) => {
+                    if seen.insert(ptr as uint) {
+                        let node_data = node.get_data();
+                        collect(&node_data.left, seen);
+                        collect(&node_data.right, seen);
+                    }
+                }
+                None => {}
+            }
+        }
+
+        let mut seen = HashSet::new();
+        let mut logical_nodes = 0u;
+
+        for version in versions.iter() {
+            collect(&version.root, &mut seen);
+            logical_nodes += version.len;
+        }
+
+        SharingReport {
+            distinct_nodes: seen.len(),
+            logical_nodes: logical_nodes
+        }
+    }
+
+    // Groups the entries by the discriminant `disc` computes from their values. Returns a tree
+    // mapping each discriminant to a tree of the entries it was computed for.
+    pub fn split_by<D: Ord+Clone+Send+Sync, OIS: ItemStore<D, RedBlackTree<K, V, IS>>>(
+                    &self,
+                    disc: |&V| -> D)
+                 -> RedBlackTree<D, RedBlackTree<K, V, IS>, OIS> {
+        let mut tagged: Vec<(D, IS)> = Vec::with_capacity(self.len);
+        let mut entries = self.iter();
+
+        loop {
+            match entries.next_item() {
+                Some(item) => tagged.push((disc(item.val()), item.clone())),
+                None => break
+            }
+        }
+
+        // The sort is stable, so the entries of each group stay in key order
+        tagged.sort_by(|a, b| a.ref0().cmp(b.ref0()));
+
+        let mut groups: Vec<OIS> = Vec::new();
+        let mut tagged = tagged.move_iter().peekable();
+
+        loop {
+            let (group_key, first) = match tagged.next() {
+                Some(entry) => entry,
+                None => break
+            };
+
+            let mut items = vec![first];
+            loop {
+                match tagged.peek() {
+                    Some(&(ref next_key, _)) if *next_key == group_key => {}
+                    _ => break
+                }
+                items.push(tagged.next().unwrap().val1());
+            }
+
+            groups.push(ItemStore::new(group_key, RedBlackTree::from_sorted(items)));
+        }
+
+        RedBlackTree::from_sorted(groups)
+    }
+
+    // Returns a tree with the same entries, ordered by `cmp` instead of the keys' `Ord`
+    // implementation. If `cmp` considers two keys equal, the entry with the greater key (by `Ord`)
+    // is kept.
+    pub fn reorder_by<CIS: ItemStore<CmpKey<K>, V>>(&self, cmp: fn(&K, &K) -> Ordering)
+                                                    -> RedBlackTree<CmpKey<K>, V, CIS> {
+        let mut entries: Vec<(CmpKey<K>, V)> = self.iter()
+            .map(|(key, val)| (CmpKey::new(key.clone(), cmp), val.clone()))
+            .collect();
+        entries.sort_by(|a, b| a.ref0().cmp(b.ref0()));
+
+        let mut items: Vec<CIS> = Vec::with_capacity(entries.len());
+        for (key, val) in entries.move_iter() {
+            let duplicate = match items.last() {
+                Some(last) => *last.key() == key,
+                None => false
+            };
+            if duplicate {
+                items.pop();
+            }
+            items.push(ItemStore::new(key, val));
+        }
+
+        RedBlackTree::from_sorted(items)
+    }
+
+    // Replaces the values of existing keys, ignoring keys that are not contained in the tree. All
+    // updates are applied in a single descent, copying each affected path only once.
+    pub fn update_many<I: Iterator<(K, V)>>(self, updates: I) -> RedBlackTree<K, V, IS> {
+        let mut items: Vec<IS> = updates.map(|(key, val)| ItemStore::new(key, val)).collect();
+        if !items.windows(2).all(|pair| *pair[0].key() <= *pair[1].key()) {
+            // Stable, so the last update for a key still wins
+            items.sort_by(|a, b| a.key().cmp(b.key()));
+        }
+
+        RedBlackTree {
+            root: self.root.update_sorted(items.as_slice()),
+            len: self.len
+        }
+    }
+
+    // Iterates over the key-value pairs with keys in `[from, to)` in ascending key order
+    pub fn range<'a>(&'a self, from: &K, to: &'a K) -> Range<'a, K, V, IS> {
+        Range {
+            entries: Entries::starting_at(self, from),
+            to: to
+        }
+    }
+
+    // Returns a new tree containing copies of the entries with keys in `[from, to)`. Unlike the
+    // result of `clone()`, the new tree does not share any nodes with this one.
+    pub fn subtree_clone(&self, from: &K, to: &K) -> RedBlackTree<K, V, IS> {
+        let mut entries = Entries::starting_at(self, from);
+        let mut items: Vec<IS> = Vec::new();
+
+        loop {
+            match entries.next_item() {
+                Some(item) if *item.key() < *to => items.push(item.clone()),
+                _ => break
+            }
+        }
+
+        RedBlackTree::from_sorted(items)
+    }
+
+    // Cuts the entries with keys in `[from, to)` out of the tree. Returns the remaining tree and a
+    // tree of the extracted entries. Only splits and concatenates trees, so it takes O(log n)
+    // time.
+    pub fn extract_range(self, from: &K, to: &K)
+                         -> (RedBlackTree<K, V, IS>, RedBlackTree<K, V, IS>) {
+        let start = self.rank(from);
+        let end = ::std::cmp::max(start, self.rank(to));
+        let (before, rest) = self.split_at_rank(start);
+        let (extracted, after) = rest.split_at_rank(end - start);
+        (RedBlackTree::concat(before, after), extracted)
+    }
+
+    // Replaces all entries with keys in `[from, to)` by the entries of `replacement`, whose keys
+    // must lie in the same range. Only splits and concatenates trees, so it takes O(log n) time
+    // regardless of how many entries are replaced.
+    pub fn splice_range(self,
+                        from: &K,
+                        to: &K,
+                        replacement: RedBlackTree<K, V, IS>)
+                     -> RedBlackTree<K, V, IS> {
+        debug_assert!(replacement.len == 0 ||
+                      (*replacement.root.find_min_kvp().key() >= *from &&
+                       *replacement.root.find_max_kvp().key() < *to));
+
+        let start = self.rank(from);
+        let end = ::std::cmp::max(start, self.rank(to));
+        let (before, rest) = self.split_at_rank(start);
+        let (_, after) = rest.split_at_rank(end - start);
+        RedBlackTree::concat(RedBlackTree::concat(before, replacement), after)
+    }
+
+    // Folds all values, in ascending key order, into a single result. `map_fn` turns each value
+    // into an element of the monoid given by `identity` and `combine`. Visits every entry, so
+    // this suits aggregates that are needed only rarely.
+    pub fn aggregate<M>(&self, identity: M, map_fn: |&V| -> M, combine: |M, M| -> M) -> M {
+        let mut result = identity;
+        for (_, val) in self.iter() {
+            result = combine(result, map_fn(val));
+        }
+        result
+    }
+
+    // Walks the entries in ascending key order and replaces adjacent pairs for which `merge`
+    // returns a combined entry with that entry. The combined entry is then checked against the
+    // entry following the pair, so runs of any length collapse into one. Combined keys must keep
+    // the entries in ascending order.
+    pub fn coalesce(&self, merge: |&K, &V, &K, &V| -> Option<(K, V)>) -> RedBlackTree<K, V, IS> {
+        let mut builder = SortedBuilder::new();
+        let mut current: Option<(K, V)> = None;
+
+        for (key, val) in self.iter() {
+            current = match current {
+                Some((current_key, current_val)) => {
+                    match merge(&current_key, &current_val, key, val) {
+                        Some(merged) => Some(merged),
+                        None => {
+                            builder.push(current_key, current_val);
+                            Some((key.clone(), val.clone()))
+                        }
+                    }
+                }
+                None => Some((key.clone(), val.clone()))
+            };
+        }
+
+        match current {
+            Some((key, val)) => builder.push(key, val),
+            None => {}
+        }
+
+        builder.finish()
+    }
+
+    // Returns a tree with the same keys, where each value is the accumulation of all entries up
+    // to and including its key. `f` computes the next accumulated value from the previous one and
+    // the current entry, starting with `init`.
+    pub fn scan<B: Clone+Send+Sync, BIS: ItemStore<K, B>>(&self, init: B, f: |&B, &K, &V| -> B)
+                                                          -> RedBlackTree<K, B, BIS> {
+        let mut items: Vec<BIS> = Vec::with_capacity(self.len);
+        let mut acc = init;
+
+        for (key, val) in self.iter() {
+            acc = f(&acc, key, val);
+            items.push(ItemStore::new(key.clone(), acc.clone()));
+        }
+
+        RedBlackTree::from_sorted(items)
+    }
+
+    // Collects references to all entries, in ascending key order, into a vector of exactly the
+    // right size. Nothing is cloned.
+    pub fn entries_ref<'a>(&'a self) -> Vec<(&'a K, &'a V)> {
+        let mut entries = Vec::with_capacity(self.len);
+        entries.extend(self.iter());
+        entries
+    }
+
+    // Iterates over all keys in ascending order
+    pub fn keys<'a>(&'a self) -> Keys<'a, K, V, IS> {
+        Keys { entries: self.iter() }
+    }
+
+    // Iterates over all values in ascending order of their keys
+    pub fn values<'a>(&'a self) -> Values<'a, K, V, IS> {
+        Values { entries: self.iter() }
+    }
+
+    // Returns clones of all keys in ascending order
+    pub fn keys_vec(&self) -> Vec<K> {
+        let mut keys = Vec::with_capacity(self.len);
+        for (key, _) in self.iter() {
+            keys.push(key.clone());
+        }
+        keys
+    }
+
+    // Returns the keys and the values in ascending key order as two vectors, so that `vals[i]` is
+    // the value of `keys[i]`. Nodes may be shared with other trees, so the entries are cloned.
+    pub fn into_columns(self) -> (Vec<K>, Vec<V>) {
+        let mut keys = Vec::with_capacity(self.len);
+        let mut vals = Vec::with_capacity(self.len);
+
+        for (key, val) in self.iter() {
+            keys.push(key.clone());
+            vals.push(val.clone());
+        }
+
+        (keys, vals)
+    }
+
+    // Consumes the tree and yields all entries as owned pairs in ascending key order. Only
+    // allocates a stack with one slot per tree level.
+    pub fn move_iter(self) -> IntoIter<K, V, IS> {
+        IntoIter::new(self)
+    }
+
+    // Alias of `move_iter()`, which is the name used by the standard collections
+    pub fn into_sorted_iter(self) -> IntoIter<K, V, IS> {
+        self.move_iter()
+    }
+
+    // Iterates over all key-value pairs in ascending key order, together with their position in
+    // that order
+    pub fn iter_with_rank<'a>(&'a self) -> RankedEntries<'a, K, V, IS> {
+        RankedEntries::new(self)
+    }
+
+    // Returns the entry at position `index` in ascending key order
+    pub fn select<'a>(&'a self, index: uint) -> Option<(&'a K, &'a V)> {
+        self.root.select(index).map(|kvp| (kvp.key(), kvp.val()))
+    }
+
+    // Returns the entries on the zero-based page `page_number` of the tree, with `page_size`
+    // entries per page. Seeks directly to the first entry on the page, so this takes
+    // O(log n + page_size) time for any page.
+    pub fn page<'a>(&'a self, page_number: uint, page_size: uint) -> Vec<(&'a K, &'a V)> {
+        let start = page_number * page_size;
+        match self.select(start) {
+            Some((first_key, _)) => Entries::starting_at(self, first_key).take(page_size).collect(),
+            None => Vec::new()
+        }
+    }
+
+    // Returns `count` entries in ascending key order, starting at position `start` and wrapping
+    // around to the first entry after the last one
+    pub fn window_from_rank<'a>(&'a self, start: uint, count: uint) -> Vec<(&'a K, &'a V)> {
+        let mut window = Vec::with_capacity(count);
+        if self.len == 0 {
+            return window;
+        }
+
+        let mut entries = match self.select(start % self.len) {
+            Some((key, _)) => Entries::starting_at(self, key),
+            None => unreachable!()
+        };
+
+        while window.len() < count {
+            match entries.next() {
+                Some(entry) => window.push(entry),
+                None => entries = self.iter()
+            }
+        }
+
+        window
+    }
+
+    // Returns the middle entry in key order. For an even number of entries this is the lower of
+    // the two middle entries, i.e. the one at position `len / 2 - 1`.
+    pub fn median<'a>(&'a self) -> Option<(&'a K, &'a V)> {
+        if self.len == 0 {
+            None
+        } else {
+            self.select((self.len - 1) / 2)
+        }
+    }
+
+    // Returns up to `k` of the largest entries in descending key order. Only visits the nodes
+    // needed for finding them, which are O(k + log n).
+    pub fn top_k<'a>(&'a self, k: uint) -> Vec<(&'a K, &'a V)> {
+        let mut items = Vec::with_capacity(::std::cmp::min(k, self.len));
+        self.root.collect_largest(k, &mut items);
+        items.iter().map(|item| (item.key(), item.val())).collect()
+    }
+
+    // Returns up to `k` of the smallest entries in ascending key order. Only visits the nodes
+    // needed for finding them, which are O(k + log n).
+    pub fn bottom_k<'a>(&'a self, k: uint) -> Vec<(&'a K, &'a V)> {
+        let mut items = Vec::with_capacity(::std::cmp::min(k, self.len));
+        self.root.collect_smallest(k, &mut items);
+        items.iter().map(|item| (item.key(), item.val())).collect()
+    }
+
+    // Lists all entries level by level, starting at the root, together with their depth and color.
+    // Within a level, entries are in ascending key order.
+    pub fn level_order<'a>(&'a self) -> Vec<(uint, Color, &'a K, &'a V)> {
+        let mut entries = Vec::with_capacity(self.len);
+        let mut queue = RingBuf::new();
+        queue.push_back((0u, &self.root));
+
+        loop {
+            let (depth, node) = match queue.pop_front() {
+                Some(next) => next,
+                None => break
+            };
+
+            if !node.is_leaf() {
+                let node_data = node.get_data();
+                entries.push((depth, node.col, node_data.item.key(), node_data.item.val()));
+                queue.push_back((depth + 1, &node_data.left));
+                queue.push_back((depth + 1, &node_data.right));
+            }
+        }
+
+        entries
+    }
+
+    // Returns the number of keys in the tree that are less than `key`
+    pub fn rank(&self, key: &K) -> uint {
+        self.root.rank(key)
+    }
+
+    // Returns the entry for `key`, which allows for deciding later whether and what to insert
+    pub fn entry_owned(&self, key: K) -> OwnedEntry<K, V, IS> {
+        let mut path = Vec::new();
+        let mut node = &self.root;
+
+        while !node.is_leaf() {
+            let node_data = node.get_data();
+
+            if key < *node_data.item.key() {
+                path.push((node.clone(), true));
+                node = &node_data.left;
+            } else if key > *node_data.item.key() {
+                path.push((node.clone(), false));
+                node = &node_data.right;
+            } else {
+                break;
+            }
+        }
+
+        OwnedEntry {
+            path: path,
+            node: node.clone(),
+            key: key,
+            len: self.len
+        }
+    }
+
+    // Wraps this version of the tree in an `Arc` for sharing it with other tasks, e.g. via a
+    // `Publisher`
+    pub fn publish(self) -> Arc<RedBlackTree<K, V, IS>> {
+        Arc::new(self)
+    }
+
+    // Presents the tree as a sorted, slice-like sequence of entries
+    pub fn positional<'a>(&'a self) -> PositionalView<'a, K, V, IS> {
+        PositionalView { tree: self }
+    }
+
+    // Checks whether both trees contain the same keys, comparing the values of each key with
+    // `value_eq`. Stops at the first mismatch.
+    pub fn eq_by(&self, other: &RedBlackTree<K, V, IS>, value_eq: |&V, &V| -> bool) -> bool {
+        if self.len != other.len {
+            return false;
+        }
+
+        for ((k1, v1), (k2, v2)) in self.iter().zip(other.iter()) {
+            if *k1 != *k2 || !value_eq(v1, v2) {
+                return false;
+            }
+        }
+
+        true
+    }
+
+    // Finds the longest run of entries in which each key is the successor of the previous one,
+    // according to `is_successor`. Returns the length of the run and its first and last entry. Of
+    // several runs of the same length, the first one is returned.
+    pub fn longest_run<'a>(&'a self, is_successor: |&K, &K| -> bool)
+                           -> Option<(uint, (&'a K, &'a V), (&'a K, &'a V))> {
+        let mut entries = self.iter();
+        let first = match entries.next() {
+            Some(entry) => entry,
+            None => return None
+        };
+
+        let mut longest = (1u, first, first);
+        let (mut run_len, mut run_start, mut last) = longest;
+
+        for entry in entries {
+            if is_successor(last.val0(), entry.val0()) {
+                run_len += 1;
+            } else {
+                run_len = 1;
+                run_start = entry;
+            }
+
+            last = entry;
+
+            if run_len > longest.val0() {
+                longest = (run_len, run_start, last);
+            }
+        }
+
+        Some(longest)
+    }
+
+    // Iterates over the entries of all given trees in ascending key order without building a
+    // combined tree. Entries with equal keys are returned in the order of their trees.
+    pub fn merge_all<'a>(trees: &'a [RedBlackTree<K, V, IS>]) -> KWayMerge<'a, K, V, IS> {
+        KWayMerge::new(trees)
+    }
+
+    // Iterates over the gaps between adjacent keys, in ascending key order. `gap` measures the
+    // distance between two adjacent keys, returning `None` if there is no gap between them. Yields
+    // each gap together with the entries on both of its sides.
+    pub fn gaps<'a, G>(&'a self, gap: |&K, &K|:'a -> Option<G>) -> Gaps<'a, K, V, IS, G> {
+        Gaps {
+            entries: self.iter(),
+            previous: None,
+            gap: gap
+        }
+    }
+
+    // Groups the entries, in ascending key order, into maximal runs where each pair of adjacent
+    // keys satisfies `is_contiguous`
+    pub fn runs<'a>(&'a self, is_contiguous: |&K, &K|:'a -> bool) -> Runs<'a, K, V, IS> {
+        Runs {
+            entries: self.iter().peekable(),
+            is_contiguous: is_contiguous
+        }
+    }
+}
+
+impl<K: Hash+Eq+Send+Sync+Ord+Clone, V: Send+Sync+Clone> PersistentMap<K, V> for RedBlackTree<K, V, CopyStore<K, V>> {
+    #[inline]
+    fn insert(self, key: K, value: V) -> (RedBlackTree<K, V, CopyStore<K, V>>, bool) {
+        self.insert(CopyStore::new(key, value))
+    }
+
+    #[inline]
+    fn remove(self, key: &K) -> (RedBlackTree<K, V, CopyStore<K, V>>, bool) {
+        self.remove(key)
+    }
+}
+
+impl<K: Hash+Eq+Send+Sync+Ord+Clone, V: Send+Sync+Clone> PersistentMap<K, V> for RedBlackTree<K, V, ShareStore<K, V>> {
+    #[inline]
+    fn insert(self, key: K, value: V) -> (RedBlackTree<K, V, ShareStore<K, V>>, bool) {
+        self.insert(ShareStore::new(key, value))
+    }
+
+    #[inline]
+    fn remove(self, key: &K) -> (RedBlackTree<K, V, ShareStore<K, V>>, bool) {
+        self.remove(key)
     }
 }
 
@@ -610,21 +2735,3234 @@ impl<K: Hash+Eq+Send+Sync+Ord+Clone, V: Send+Sync+Clone, IS: ItemStore<K, V>> Ma
     fn find<'a>(&'a self, key: &K) -> Option<&'a V> {
         self.find(key)
     }
-}
 
-impl<K: Hash+Eq+Send+Sync+Ord+Clone, V: Send+Sync+Clone, IS: ItemStore<K, V>> Collection for RedBlackTree<K, V, IS> {
-    #[inline]
-    fn len(&self) -> uint {
-        self.len
+    #[inline]
+    fn contains_key(&self, key: &K) -> bool {
+        self.contains_key(key)
+    }
+}
+
+impl<K: Hash+Eq+Send+Sync+Ord+Clone, V: Send+Sync+Clone, IS: ItemStore<K, V>> Collection for RedBlackTree<K, V, IS> {
+    #[inline]
+    fn len(&self) -> uint {
+        self.len
+    }
+}
+
+impl<K: Ord+Clone+Send+Sync, V: Clone+Send+Sync, IS: ItemStore<K, V>> ReadOnlyMap<K, V> for RedBlackTree<K, V, IS> {
+    fn get<'a>(&'a self, key: &K) -> Option<&'a V> {
+        self.find(key)
+    }
+
+    fn len(&self) -> uint {
+        self.len
+    }
+}
+
+impl<K: Ord+Clone+Send+Sync, V: Clone+Send+Sync, IS: ItemStore<K, V>> FromIterator<(K, V)> for RedBlackTree<K, V, IS> {
+    // Later pairs win over earlier ones with the same key, just like repeated inserts
+    fn from_iter<T: Iterator<(K, V)>>(iter: T) -> RedBlackTree<K, V, IS> {
+        let mut tree = RedBlackTree::new();
+
+        for (key, value) in iter {
+            tree = tree.insert(ItemStore::new(key, value)).val0();
+        }
+
+        tree
+    }
+}
+
+impl<K: Ord+Clone+Send+Sync, V: Clone+Send+Sync, IS: ItemStore<K, V>> Extendable<(K, V)> for RedBlackTree<K, V, IS> {
+    fn extend<T: Iterator<(K, V)>>(&mut self, iter: T) {
+        for (key, value) in iter {
+            let tree = mem::replace(self, RedBlackTree::new());
+            *self = tree.insert(ItemStore::new(key, value)).val0();
+        }
+    }
+}
+
+// Prints the entries in key order, e.g. `{1: a, 2: b}`; node colors are left out
+impl<K: Ord+Clone+Send+Sync+fmt::Show, V: Clone+Send+Sync+fmt::Show, IS: ItemStore<K, V>> fmt::Show for RedBlackTree<K, V, IS> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "{{"));
+
+        for (i, (key, value)) in self.iter().enumerate() {
+            if i != 0 {
+                try!(write!(f, ", "));
+            }
+            try!(write!(f, "{}: {}", *key, *value));
+        }
+
+        write!(f, "}}")
+    }
+}
+
+// Two trees are equal if they hold the same entries, no matter how their nodes are shaped
+impl<K: Ord+Clone+Send+Sync, V: Eq+Clone+Send+Sync, IS: ItemStore<K, V>> PartialEq for RedBlackTree<K, V, IS> {
+    fn eq(&self, other: &RedBlackTree<K, V, IS>) -> bool {
+        self.len == other.len &&
+        self.iter().zip(other.iter()).all(|((k1, v1), (k2, v2))| *k1 == *k2 && *v1 == *v2)
+    }
+}
+
+impl<K: Ord+Clone+Send+Sync, V: Eq+Clone+Send+Sync, IS: ItemStore<K, V>> Eq for RedBlackTree<K, V, IS> {}
+
+// Which entry a BoundedMap gives up when it grows beyond its capacity
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub enum Eviction {
+    EvictSmallest,
+    EvictLargest,
+}
+
+// A RedBlackTree holding at most `max_len` entries. Inserting a new key into a full map evicts the
+// smallest or largest key, depending on the eviction policy, so the map acts as a bounded window
+// over the key range.
+pub struct BoundedMap<K, V, IS> {
+    tree: RedBlackTree<K, V, IS>,
+    max_len: uint,
+    eviction: Eviction,
+}
+
+impl<K, V, IS: ItemStore<K, V>> Clone for BoundedMap<K, V, IS> {
+    fn clone(&self) -> BoundedMap<K, V, IS> {
+        BoundedMap {
+            tree: self.tree.clone(),
+            max_len: self.max_len,
+            eviction: self.eviction
+        }
+    }
+}
+
+impl<K: Ord+Clone+Send+Sync, V: Clone+Send+Sync, IS: ItemStore<K, V>> BoundedMap<K, V, IS> {
+    pub fn new(max_len: uint, eviction: Eviction) -> BoundedMap<K, V, IS> {
+        assert!(max_len > 0);
+        BoundedMap {
+            tree: RedBlackTree::new(),
+            max_len: max_len,
+            eviction: eviction
+        }
+    }
+
+    pub fn tree<'a>(&'a self) -> &'a RedBlackTree<K, V, IS> {
+        &self.tree
+    }
+
+    pub fn find<'a>(&'a self, search_key: &K) -> Option<&'a V> {
+        self.tree.find(search_key)
+    }
+
+    pub fn len(&self) -> uint {
+        self.tree.len
+    }
+
+    // Inserts a key-value pair. If this pushes the map over its capacity, the entry selected by
+    // the eviction policy is removed and returned.
+    pub fn insert(self, kvp: IS) -> (BoundedMap<K, V, IS>, Option<(K, V)>) {
+        let BoundedMap { tree, max_len, eviction } = self;
+        let (mut tree, _) = tree.insert(kvp);
+        let mut evicted = None;
+
+        if tree.len > max_len {
+            let (key, val) = {
+                let kvp = match eviction {
+                    EvictSmallest => tree.root.find_min_kvp(),
+                    EvictLargest => tree.root.find_max_kvp(),
+                };
+                (kvp.key().clone(), kvp.val().clone())
+            };
+            tree = tree.remove(&key).val0();
+            evicted = Some((key, val));
+        }
+
+        assert!(tree.len <= max_len);
+        (BoundedMap { tree: tree, max_len: max_len, eviction: eviction }, evicted)
+    }
+}
+
+// A piecewise-constant function over `K`. Each entry of the underlying tree marks the start of a
+// segment, which extends up to the next entry. Points before the first segment have no value.
+pub struct StepMap<K, V, IS> {
+    tree: RedBlackTree<K, V, IS>,
+}
+
+impl<K, V, IS: ItemStore<K, V>> Clone for StepMap<K, V, IS> {
+    fn clone(&self) -> StepMap<K, V, IS> {
+        StepMap {
+            tree: self.tree.clone()
+        }
+    }
+}
+
+impl<K: Ord+Clone+Send+Sync, V: Clone+Send+Sync, IS: ItemStore<K, V>> StepMap<K, V, IS> {
+    pub fn new() -> StepMap<K, V, IS> {
+        StepMap {
+            tree: RedBlackTree::new()
+        }
+    }
+
+    pub fn tree<'a>(&'a self) -> &'a RedBlackTree<K, V, IS> {
+        &self.tree
+    }
+
+    // The number of segments
+    pub fn len(&self) -> uint {
+        self.tree.len
+    }
+
+    // Starts a new segment with value `val` at `start`. It replaces the segment starting at the
+    // same point, if any, and ends where the next segment starts.
+    pub fn set_range(self, start: K, val: V) -> StepMap<K, V, IS> {
+        StepMap {
+            tree: self.tree.insert(ItemStore::new(start, val)).val0()
+        }
+    }
+
+    // Returns the value of the segment containing `point`
+    pub fn lookup<'a>(&'a self, point: &K) -> Option<&'a V> {
+        self.tree.floor(point).map(|(_, val)| val)
+    }
+}
+
+// A map with a fixed capacity that evicts the least recently used entry when it grows beyond it.
+// Accesses are recorded in two companion trees, one from key to the time of the last access and
+// one from that time back to the key, so the least recently used key is always the first entry of
+// the latter.
+pub struct LruMap<K, V, IS> {
+    tree: RedBlackTree<K, V, IS>,
+    last_access: RedBlackTree<K, u64, CopyStore<K, u64>>,
+    by_access: RedBlackTree<u64, K, CopyStore<u64, K>>,
+    clock: u64,
+    max_len: uint,
+}
+
+impl<K: Ord+Clone+Send+Sync, V: Clone+Send+Sync, IS: ItemStore<K, V>> LruMap<K, V, IS> {
+    pub fn new(max_len: uint) -> LruMap<K, V, IS> {
+        assert!(max_len > 0);
+        LruMap {
+            tree: RedBlackTree::new(),
+            last_access: RedBlackTree::new(),
+            by_access: RedBlackTree::new(),
+            clock: 0,
+            max_len: max_len
+        }
+    }
+
+    pub fn tree<'a>(&'a self) -> &'a RedBlackTree<K, V, IS> {
+        &self.tree
+    }
+
+    pub fn len(&self) -> uint {
+        self.tree.len
+    }
+
+    // Looks up `key` and records the access
+    pub fn get<'a>(&'a mut self, key: &K) -> Option<&'a V> {
+        if self.tree.find(key).is_some() {
+            self.touch(key);
+        }
+        self.tree.find(key)
+    }
+
+    // Inserts a key-value pair, which counts as an access. If this pushes the map over its
+    // capacity, the least recently used entry is removed and returned.
+    pub fn insert(&mut self, kvp: IS) -> Option<(K, V)> {
+        let key = kvp.key().clone();
+        self.tree = self.tree.clone().insert(kvp).val0();
+        self.touch(&key);
+
+        if self.tree.len <= self.max_len {
+            return None;
+        }
+
+        let lru_key = self.by_access.root.find_min_kvp().val().clone();
+        let lru_val = self.tree.find(&lru_key).unwrap().clone();
+        let lru_time = *self.last_access.find(&lru_key).unwrap();
+
+        self.tree = self.tree.clone().remove(&lru_key).val0();
+        self.last_access = self.last_access.clone().remove(&lru_key).val0();
+        self.by_access = self.by_access.clone().remove(&lru_time).val0();
+
+        assert!(self.tree.len == self.max_len);
+        Some((lru_key, lru_val))
+    }
+
+    fn touch(&mut self, key: &K) {
+        match self.last_access.find(key) {
+            Some(&time) => self.by_access = self.by_access.clone().remove(&time).val0(),
+            None => {}
+        }
+
+        self.clock += 1;
+        self.last_access = self.last_access.clone().insert(CopyStore::new(key.clone(), self.clock))
+                                                   .val0();
+        self.by_access = self.by_access.clone().insert(CopyStore::new(self.clock, key.clone()))
+                                               .val0();
+    }
+}
+
+impl<K: Ord+Clone+Send+Sync, V: PartialEq+Clone+Send+Sync, IS: ItemStore<K, V>> RedBlackTree<K, V, IS> {
+    // Same as `insert()`, but if the key is already mapped to an equal value, the tree is returned
+    // as it is instead of copying the path to the key. This keeps re-setting a value from
+    // allocating and from breaking node sharing with previous versions.
+    pub fn insert_if_changed(self, kvp: IS) -> (RedBlackTree<K, V, IS>, bool) {
+        self.insert_item(kvp, None, &mut |stored, kvp| {
+            if *stored.val() == *kvp.val() { None } else { Some(kvp) }
+        })
+    }
+
+    // Returns a tree with the same keys and the values transformed by `f`. `policy` decides
+    // whether unchanged parts of the tree are shared with this one or copied.
+    pub fn map_values_with_policy(&self, policy: SharePolicy, f: |&V| -> V)
+                                  -> RedBlackTree<K, V, IS> {
+        fn map_node<K: Ord+Clone+Send+Sync,
+                    V: PartialEq+Clone+Send+Sync,
+                    IS: ItemStore<K, V>>(
+                        node: &NodeRef<K, V, IS>,
+                        policy: SharePolicy,
+                        f: &mut |&V| -> V)
+                     -> NodeRef<K, V, IS> {
+            if node.is_leaf() {
+                return node.clone();
+            }
+
+            let node_data = node.get_data();
+            let left = map_node(&node_data.left, policy, f);
+            let val = (*f)(node_data.item.val());
+            let right = map_node(&node_data.right, policy, f);
+
+            if policy == ShareUnchanged &&
+               val == *node_data.item.val() &&
+               left.node_ptr() == node_data.left.node_ptr() &&
+               right.node_ptr() == node_data.right.node_ptr() {
+                node.clone()
+            } else {
+                new_node(node.col, left, ItemStore::new(node_data.item.key().clone(), val), right)
+            }
+        }
+
+        let mut f = f;
+        RedBlackTree {
+            root: map_node(&self.root, policy, &mut f),
+            len: self.len
+        }
+    }
+
+    // Is every entry of this tree also contained in `other`, with an equal value? Walks both trees
+    // in key order and stops at the first entry that is missing from or different in `other`.
+    pub fn is_submap_of(&self, other: &RedBlackTree<K, V, IS>) -> bool {
+        if self.len > other.len {
+            return false;
+        }
+
+        self.zip_all(other).all(|(_, val, other_val)| {
+            match (val, other_val) {
+                (Some(val), Some(other_val)) => *val == *other_val,
+                (Some(_), None) => false,
+                (None, _) => true
+            }
+        })
+    }
+
+    // Computes the changes that turn this map into `other`
+    pub fn diff(&self, other: &RedBlackTree<K, V, IS>) -> Changes<K, V> {
+        let mut changes = Changes {
+            added: Vec::new(),
+            removed: Vec::new(),
+            changed: Vec::new()
+        };
+        let mut old_entries = self.iter();
+        let mut new_entries = other.iter();
+        let mut next_old = old_entries.next();
+        let mut next_new = new_entries.next();
+
+        loop {
+            match (next_old, next_new) {
+                (None, None) => break,
+                (Some((old_key, _)), None) => {
+                    changes.removed.push(old_key.clone());
+                    next_old = old_entries.next();
+                }
+                (None, Some((new_key, new_val))) => {
+                    changes.added.push((new_key.clone(), new_val.clone()));
+                    next_new = new_entries.next();
+                }
+                (Some((old_key, old_val)), Some((new_key, new_val))) => {
+                    if *old_key < *new_key {
+                        changes.removed.push(old_key.clone());
+                        next_old = old_entries.next();
+                    } else if *old_key > *new_key {
+                        changes.added.push((new_key.clone(), new_val.clone()));
+                        next_new = new_entries.next();
+                    } else {
+                        if *old_val != *new_val {
+                            changes.changed.push((new_key.clone(), new_val.clone()));
+                        }
+                        next_old = old_entries.next();
+                        next_new = new_entries.next();
+                    }
+                }
+            }
+        }
+
+        changes
+    }
+}
+
+impl<K: Ord+Clone+Send+Sync, IS: ItemStore<K, u64>> RedBlackTree<K, u64, IS> {
+    // Treats both trees as multisets mapping keys to counts and returns their sum. Keys contained
+    // in only one of the trees keep their count.
+    pub fn add_counts(self, other: &RedBlackTree<K, u64, IS>) -> RedBlackTree<K, u64, IS> {
+        RedBlackTree::from_sorted(self.merge_items(other, |left, right| {
+            Some(ItemStore::new(left.key().clone(), *left.val() + *right.val()))
+        }))
+    }
+}
+
+impl<K: Ord+Clone+Send+Sync, IS: ItemStore<K, f64>> RedBlackTree<K, f64, IS> {
+    // Returns `self[k] - other[k]` for every key contained in either tree, with missing values
+    // taken as zero
+    pub fn numeric_diff(&self, other: &RedBlackTree<K, f64, IS>) -> RedBlackTree<K, f64, IS> {
+        let mut items: Vec<IS> = Vec::with_capacity(self.len + other.len);
+
+        for (key, left, right) in self.zip_all(other) {
+            let delta = left.map_or(0.0, |&val| val) - right.map_or(0.0, |&val| val);
+            items.push(ItemStore::new(key.clone(), delta));
+        }
+
+        RedBlackTree::from_sorted(items)
+    }
+}
+
+impl<K: Ord+Clone+Send+Sync, V: Clone+Send+Sync> RedBlackTree<K, V, InternedValueStore<K, V>> {
+    // Maps `key` to the value behind `val` without copying it. Inserting the same `Arc` for many
+    // keys keeps a single allocation for all of them.
+    pub fn set_interned(self, key: K, val: Arc<V>)
+                        -> (RedBlackTree<K, V, InternedValueStore<K, V>>, bool) {
+        self.insert(InternedValueStore::from_arc(key, val))
+    }
+}
+
+// The slot for a key in a specific version of a tree, which may or may not be occupied. The entry
+// holds its own references to the nodes on the path to the key, so it is independent of the tree
+// it was obtained from and a value can be inserted at any later point. Obtained via
+// `RedBlackTree::entry_owned()`.
+pub struct OwnedEntry<K, V, IS> {
+    // The nodes on the path to the key, and whether the path went left at each one
+    path: Vec<(NodeRef<K, V, IS>, bool)>,
+    // The node holding the key, or the leaf where it would be inserted
+    node: NodeRef<K, V, IS>,
+    key: K,
+    len: uint,
+}
+
+impl<K: Ord+Clone+Send+Sync, V: Clone+Send+Sync, IS: ItemStore<K, V>> OwnedEntry<K, V, IS> {
+    pub fn key<'a>(&'a self) -> &'a K {
+        &self.key
+    }
+
+    pub fn is_vacant(&self) -> bool {
+        self.node.is_leaf()
+    }
+
+    // The value currently stored for the key, if any
+    pub fn get<'a>(&'a self) -> Option<&'a V> {
+        if self.node.is_leaf() {
+            None
+        } else {
+            Some(self.node.get_data().item.val())
+        }
+    }
+
+    // Stores `value` for the key, returning the resulting version of the tree the entry was
+    // obtained from
+    pub fn insert(self, value: V) -> RedBlackTree<K, V, IS> {
+        let OwnedEntry { path, node, key, len } = self;
+        let kvp: IS = ItemStore::new(key, value);
+
+        let (mut result, len) = if node.is_leaf() {
+            (new_node(Red, new_leaf(Black), kvp, new_leaf(Black)), len + 1)
+        } else {
+            let node_data = node.get_data();
+            (new_node(node.col, node_data.left.clone(), kvp, node_data.right.clone()), len)
+        };
+
+        for &(ref parent, went_left) in path.iter().rev() {
+            let parent_data = parent.get_data();
+            result = if went_left {
+                new_node(parent.col, result, parent_data.item.clone(), parent_data.right.clone())
+            } else {
+                new_node(parent.col, parent_data.left.clone(), parent_data.item.clone(), result)
+            }.balance();
+        }
+
+        RedBlackTree {
+            root: result.blacken(),
+            len: len
+        }
+    }
+}
+
+// Builds a balanced tree from entries pushed in strictly increasing key order, without knowing
+// their number in advance. Only O(log n) partially built subtrees are kept while building.
+pub struct SortedBuilder<K, V, IS> {
+    // Perfectly balanced, all-black subtrees, each followed by the item after its greatest key.
+    // The black heights of the subtrees are strictly decreasing.
+    pending: Vec<(NodeRef<K, V, IS>, uint, IS)>,
+    len: uint,
+}
+
+impl<K: Ord+Clone+Send+Sync, V: Clone+Send+Sync, IS: ItemStore<K, V>> SortedBuilder<K, V, IS> {
+    pub fn new() -> SortedBuilder<K, V, IS> {
+        SortedBuilder {
+            pending: Vec::new(),
+            len: 0
+        }
+    }
+
+    // Adds an entry. Its key must be greater than all keys pushed before.
+    pub fn push(&mut self, key: K, value: V) {
+        match self.pending.last() {
+            Some(&(_, _, ref last)) => assert!(*last.key() < key),
+            None => {}
+        }
+
+        self.pending.push((new_leaf(Black), 0, ItemStore::new(key, value)));
+        self.len += 1;
+
+        // Combine subtrees of equal height, like carrying in a binary counter
+        while self.pending.len() >= 2 {
+            let count = self.pending.len();
+            if *self.pending[count - 1].ref1() != *self.pending[count - 2].ref1() {
+                break;
+            }
+
+            let (right, height, item) = self.pending.pop().unwrap();
+            let (left, _, separator) = self.pending.pop().unwrap();
+            self.pending.push((new_node(Black, left, separator, right), height + 1, item));
+        }
+    }
+
+    // Joins the pending subtrees into the final tree
+    pub fn finish(self) -> RedBlackTree<K, V, IS> {
+        let SortedBuilder { pending, len } = self;
+        let mut root = new_leaf(Black);
+
+        for (subtree, _, item) in pending.move_iter().rev() {
+            root = join(subtree, item, root);
+        }
+
+        RedBlackTree {
+            root: root,
+            len: len
+        }
+    }
+}
+
+// Distributes new versions of a tree to any number of concurrent readers. The current version is
+// kept behind an `RWLock`, so loads are not lock-free: every `load()` takes the read lock and a
+// `publish()` briefly blocks all readers. Both hold the lock only for as long as it takes to swap
+// or clone the pointer to the current version, and readers can then use their snapshot without
+// any further synchronization.
+pub struct Publisher<K, V, IS> {
+    current: Arc<RWLock<Arc<RedBlackTree<K, V, IS>>>>,
+}
+
+// A reader's handle to the versions distributed by a `Publisher`
+pub struct ReaderHandle<K, V, IS> {
+    current: Arc<RWLock<Arc<RedBlackTree<K, V, IS>>>>,
+}
+
+impl<K: Ord+Clone+Send+Sync, V: Clone+Send+Sync, IS: ItemStore<K, V>> Publisher<K, V, IS> {
+    pub fn new(tree: RedBlackTree<K, V, IS>) -> Publisher<K, V, IS> {
+        Publisher { current: Arc::new(RWLock::new(tree.publish())) }
+    }
+
+    // Makes `tree` the version seen by all subsequent loads
+    pub fn publish(&self, tree: RedBlackTree<K, V, IS>) {
+        let tree = tree.publish();
+        *self.current.write() = tree;
+    }
+
+    pub fn reader(&self) -> ReaderHandle<K, V, IS> {
+        ReaderHandle { current: self.current.clone() }
+    }
+}
+
+impl<K: Ord+Clone+Send+Sync, V: Clone+Send+Sync, IS: ItemStore<K, V>> ReaderHandle<K, V, IS> {
+    // Returns the most recently published version. Takes the read lock for the pointer clone.
+    pub fn load(&self) -> Arc<RedBlackTree<K, V, IS>> {
+        self.current.read().clone()
+    }
+}
+
+impl<K, V, IS> Clone for ReaderHandle<K, V, IS> {
+    fn clone(&self) -> ReaderHandle<K, V, IS> {
+        ReaderHandle { current: self.current.clone() }
+    }
+}
+
+// A view of a tree as a sorted sequence of entries that can be indexed by position, like a sorted
+// slice. Obtained via `RedBlackTree::positional()`.
+pub struct PositionalView<'a, K, V, IS> {
+    tree: &'a RedBlackTree<K, V, IS>,
+}
+
+impl<'a, K: Ord+Clone+Send+Sync, V: Clone+Send+Sync, IS: ItemStore<K, V>> PositionalView<'a, K, V, IS> {
+    // Returns the entry at position `index`
+    pub fn get(&self, index: uint) -> Option<(&'a K, &'a V)> {
+        self.tree.select(index)
+    }
+
+    pub fn len(&self) -> uint {
+        self.tree.len
+    }
+
+    // Searches for a key with the comparator `f`, which returns the ordering of the given key
+    // relative to the one searched for. Returns `Ok(position)` if it is found and
+    // `Err(position)` with the position it would be inserted at otherwise.
+    pub fn binary_search_by(&self, f: |&K| -> Ordering) -> Result<uint, uint> {
+        let mut node = &self.tree.root;
+        let mut position = 0u;
+
+        while !node.is_leaf() {
+            let node_data = node.get_data();
+
+            match f(node_data.item.key()) {
+                Greater => node = &node_data.left,
+                Less => {
+                    position += node_data.left.size() + 1;
+                    node = &node_data.right;
+                }
+                Equal => return Ok(position + node_data.left.size())
+            }
+        }
+
+        Err(position)
+    }
+
+    // Same as `binary_search_by()` comparing with `key`
+    pub fn binary_search(&self, key: &K) -> Result<uint, uint> {
+        self.binary_search_by(|k| k.cmp(key))
+    }
+}
+
+// Iterates over the entries of a tree in ascending key order. Holds at most one node reference per
+// tree level at any time.
+pub struct Entries<'a, K, V, IS> {
+    stack: Vec<&'a NodeData<K, V, IS>>,
+    remaining: uint,
+}
+
+impl<'a, K: Ord+Clone+Send+Sync, V: Clone+Send+Sync, IS: ItemStore<K, V>> Entries<'a, K, V, IS> {
+    fn new(tree: &'a RedBlackTree<K, V, IS>) -> Entries<'a, K, V, IS> {
+        let mut entries = Entries {
+            stack: Vec::new(),
+            remaining: tree.len
+        };
+        entries.push_left_spine(&tree.root);
+        entries
+    }
+
+    // Creates an iterator that starts at the first key not less than `from`
+    fn starting_at(tree: &'a RedBlackTree<K, V, IS>, from: &K) -> Entries<'a, K, V, IS> {
+        let mut entries = Entries {
+            stack: Vec::new(),
+            remaining: tree.len - tree.rank(from)
+        };
+
+        let mut node = &tree.root;
+        while !node.is_leaf() {
+            let node_data = node.get_data();
+            if *from <= *node_data.item.key() {
+                entries.stack.push(node_data);
+                node = &node_data.left;
+            } else {
+                node = &node_data.right;
+            }
+        }
+
+        entries
+    }
+
+    fn push_left_spine(&mut self, node: &'a NodeRef<K, V, IS>) {
+        let mut node = node;
+        while !node.is_leaf() {
+            let node_data = node.get_data();
+            self.stack.push(node_data);
+            node = &node_data.left;
+        }
+    }
+
+    fn next_item(&mut self) -> Option<&'a IS> {
+        match self.stack.pop() {
+            Some(node_data) => {
+                self.push_left_spine(&node_data.right);
+                self.remaining -= 1;
+                Some(&node_data.item)
+            }
+            None => None
+        }
+    }
+}
+
+impl<'a, K: Ord+Clone+Send+Sync, V: Clone+Send+Sync, IS: ItemStore<K, V>>
+Iterator<(&'a K, &'a V)> for Entries<'a, K, V, IS> {
+    fn next(&mut self) -> Option<(&'a K, &'a V)> {
+        self.next_item().map(|item| (item.key(), item.val()))
+    }
+
+    fn size_hint(&self) -> (uint, Option<uint>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+// Iterates over the keys of a tree in ascending order
+pub struct Keys<'a, K, V, IS> {
+    entries: Entries<'a, K, V, IS>,
+}
+
+impl<'a, K: Ord+Clone+Send+Sync, V: Clone+Send+Sync, IS: ItemStore<K, V>>
+Iterator<&'a K> for Keys<'a, K, V, IS> {
+    fn next(&mut self) -> Option<&'a K> {
+        self.entries.next_item().map(|item| item.key())
+    }
+
+    fn size_hint(&self) -> (uint, Option<uint>) {
+        self.entries.size_hint()
+    }
+}
+
+// Iterates over the values of a tree in ascending order of their keys
+pub struct Values<'a, K, V, IS> {
+    entries: Entries<'a, K, V, IS>,
+}
+
+impl<'a, K: Ord+Clone+Send+Sync, V: Clone+Send+Sync, IS: ItemStore<K, V>>
+Iterator<&'a V> for Values<'a, K, V, IS> {
+    fn next(&mut self) -> Option<&'a V> {
+        self.entries.next_item().map(|item| item.val())
+    }
+
+    fn size_hint(&self) -> (uint, Option<uint>) {
+        self.entries.size_hint()
+    }
+}
+
+// Consumes a tree and yields its entries as owned pairs in ascending key order. Holds at most one
+// node reference per tree level at any time. Nodes may be shared with other trees, so the entries
+// are cloned out of them.
+pub struct IntoIter<K, V, IS> {
+    stack: Vec<NodeRef<K, V, IS>>,
+    remaining: uint,
+}
+
+impl<K: Ord+Clone+Send+Sync, V: Clone+Send+Sync, IS: ItemStore<K, V>> IntoIter<K, V, IS> {
+    fn new(tree: RedBlackTree<K, V, IS>) -> IntoIter<K, V, IS> {
+        let RedBlackTree { root, len } = tree;
+        let mut entries = IntoIter {
+            stack: Vec::new(),
+            remaining: len
+        };
+        entries.push_left_spine(root);
+        entries
+    }
+
+    fn push_left_spine(&mut self, node: NodeRef<K, V, IS>) {
+        let mut node = node;
+        while !node.is_leaf() {
+            let left = node.get_data().left.clone();
+            self.stack.push(node);
+            node = left;
+        }
+    }
+}
+
+impl<K: Ord+Clone+Send+Sync, V: Clone+Send+Sync, IS: ItemStore<K, V>>
+Iterator<(K, V)> for IntoIter<K, V, IS> {
+    fn next(&mut self) -> Option<(K, V)> {
+        match self.stack.pop() {
+            Some(node) => {
+                let node_data = node.get_data();
+                self.push_left_spine(node_data.right.clone());
+                self.remaining -= 1;
+                Some((node_data.item.key().clone(), node_data.item.val().clone()))
+            }
+            None => None
+        }
+    }
+
+    fn size_hint(&self) -> (uint, Option<uint>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+// Iterates over the entries of a tree in ascending key order, together with their rank. The ranks
+// are taken from the subtree sizes stored in the nodes.
+pub struct RankedEntries<'a, K, V, IS> {
+    stack: Vec<(&'a NodeData<K, V, IS>, uint)>,
+}
+
+impl<'a, K: Ord+Clone+Send+Sync, V: Clone+Send+Sync, IS: ItemStore<K, V>> RankedEntries<'a, K, V, IS> {
+    fn new(tree: &'a RedBlackTree<K, V, IS>) -> RankedEntries<'a, K, V, IS> {
+        let mut entries = RankedEntries { stack: Vec::new() };
+        entries.push_left_spine(&tree.root, 0);
+        entries
+    }
+
+    // Pushes the left spine of a subtree whose smallest key has the rank `base`
+    fn push_left_spine(&mut self, node: &'a NodeRef<K, V, IS>, base: uint) {
+        let mut node = node;
+        while !node.is_leaf() {
+            let node_data = node.get_data();
+            self.stack.push((node_data, base + node_data.left.size()));
+            node = &node_data.left;
+        }
+    }
+}
+
+impl<'a, K: Ord+Clone+Send+Sync, V: Clone+Send+Sync, IS: ItemStore<K, V>>
+Iterator<(uint, &'a K, &'a V)> for RankedEntries<'a, K, V, IS> {
+    fn next(&mut self) -> Option<(uint, &'a K, &'a V)> {
+        match self.stack.pop() {
+            Some((node_data, rank)) => {
+                self.push_left_spine(&node_data.right, rank + 1);
+                Some((rank, node_data.item.key(), node_data.item.val()))
+            }
+            None => None
+        }
+    }
+}
+
+// Iterates over the entries of a tree with keys in a half-open range, in ascending key order. See
+// `RedBlackTree::range()`.
+pub struct Range<'a, K, V, IS> {
+    entries: Entries<'a, K, V, IS>,
+    to: &'a K,
+}
+
+impl<'a, K: Ord+Clone+Send+Sync, V: Clone+Send+Sync, IS: ItemStore<K, V>>
+Iterator<(&'a K, &'a V)> for Range<'a, K, V, IS> {
+    fn next(&mut self) -> Option<(&'a K, &'a V)> {
+        match self.entries.next() {
+            Some((key, val)) if *key < *self.to => Some((key, val)),
+            _ => {
+                self.entries.stack.clear();
+                None
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (uint, Option<uint>) {
+        (0, Some(self.entries.remaining))
+    }
+}
+
+// Groups consecutive entries into runs of contiguous keys. See `RedBlackTree::runs()`.
+pub struct Runs<'a, K, V, IS> {
+    entries: Peekable<(&'a K, &'a V), Entries<'a, K, V, IS>>,
+    is_contiguous: |&K, &K|:'a -> bool,
+}
+
+impl<'a, K: Ord+Clone+Send+Sync, V: Clone+Send+Sync, IS: ItemStore<K, V>>
+Iterator<Vec<(&'a K, &'a V)>> for Runs<'a, K, V, IS> {
+    fn next(&mut self) -> Option<Vec<(&'a K, &'a V)>> {
+        let first = match self.entries.next() {
+            Some(entry) => entry,
+            None => return None
+        };
+
+        let mut run = vec![first];
+        let mut last_key = first.val0();
+
+        loop {
+            let contiguous = match self.entries.peek() {
+                Some(&(next_key, _)) => (self.is_contiguous)(last_key, next_key),
+                None => false
+            };
+
+            if !contiguous {
+                break;
+            }
+
+            let entry = self.entries.next().unwrap();
+            last_key = entry.val0();
+            run.push(entry);
+        }
+
+        Some(run)
+    }
+}
+
+// The next entry of one of the inputs of a KWayMerge. Ordered so that the entry with the smallest
+// key, and for equal keys the one from the earliest input, is the greatest and thus comes out of
+// the heap first.
+struct MergeHead<'a, K, V> {
+    key: &'a K,
+    val: &'a V,
+    source: uint,
+}
+
+impl<'a, K: Ord, V> Ord for MergeHead<'a, K, V> {
+    fn cmp(&self, other: &MergeHead<'a, K, V>) -> Ordering {
+        match other.key.cmp(self.key) {
+            Equal => other.source.cmp(&self.source),
+            ordering => ordering
+        }
+    }
+}
+
+impl_cmp_traits!(['a, K: Ord, V] MergeHead<'a, K, V>)
+
+// Iterates over the entries of several trees in ascending key order, as if they were in a single
+// tree. Entries with equal keys are all returned, in the order of the trees they come from.
+pub struct KWayMerge<'a, K, V, IS> {
+    inputs: Vec<Entries<'a, K, V, IS>>,
+    heap: PriorityQueue<MergeHead<'a, K, V>>,
+}
+
+impl<'a, K: Ord+Clone+Send+Sync, V: Clone+Send+Sync, IS: ItemStore<K, V>> KWayMerge<'a, K, V, IS> {
+    fn new(trees: &'a [RedBlackTree<K, V, IS>]) -> KWayMerge<'a, K, V, IS> {
+        let mut merge = KWayMerge {
+            inputs: trees.iter().map(|tree| tree.iter()).collect(),
+            heap: PriorityQueue::with_capacity(trees.len())
+        };
+
+        for source in range(0, trees.len()) {
+            merge.advance(source);
+        }
+
+        merge
+    }
+
+    // Moves the next entry of the given input into the heap
+    fn advance(&mut self, source: uint) {
+        match self.inputs.get_mut(source).next() {
+            Some((key, val)) => {
+                self.heap.push(MergeHead {
+                    key: key,
+                    val: val,
+                    source: source
+                });
+            }
+            None => {}
+        }
+    }
+}
+
+impl<'a, K: Ord+Clone+Send+Sync, V: Clone+Send+Sync, IS: ItemStore<K, V>>
+Iterator<(&'a K, &'a V)> for KWayMerge<'a, K, V, IS> {
+    fn next(&mut self) -> Option<(&'a K, &'a V)> {
+        match self.heap.pop() {
+            Some(head) => {
+                self.advance(head.source);
+                Some((head.key, head.val))
+            }
+            None => None
+        }
+    }
+}
+
+// Walks two trees in lockstep, yielding every key contained in either of them together with its
+// value in each tree. See `RedBlackTree::zip_all()`.
+pub struct ZipAll<'a, K, V, W, IS, WIS> {
+    items: ZipItems<'a, K, V, W, IS, WIS>,
+}
+
+impl<'a,
+     K: Ord+Clone+Send+Sync,
+     V: Clone+Send+Sync,
+     W: Clone+Send+Sync,
+     IS: ItemStore<K, V>,
+     WIS: ItemStore<K, W>>
+Iterator<(&'a K, Option<&'a V>, Option<&'a W>)> for ZipAll<'a, K, V, W, IS, WIS> {
+    fn next(&mut self) -> Option<(&'a K, Option<&'a V>, Option<&'a W>)> {
+        self.items.next().map(|(left, right)| {
+            let key = match (left, right) {
+                (Some(item), _) => item.key(),
+                (None, Some(item)) => item.key(),
+                (None, None) => unreachable!()
+            };
+            (key, left.map(|item| item.val()), right.map(|item| item.val()))
+        })
+    }
+}
+
+// The merge walk behind `ZipAll`, and behind any other operation that combines two trees in a
+// single pass. Yields the stored items of every key contained in either tree, in key order.
+struct ZipItems<'a, K, V, W, IS, WIS> {
+    left: Entries<'a, K, V, IS>,
+    right: Entries<'a, K, W, WIS>,
+    next_left: Option<&'a IS>,
+    next_right: Option<&'a WIS>,
+}
+
+impl<'a,
+     K: Ord+Clone+Send+Sync,
+     V: Clone+Send+Sync,
+     W: Clone+Send+Sync,
+     IS: ItemStore<K, V>,
+     WIS: ItemStore<K, W>>
+Iterator<(Option<&'a IS>, Option<&'a WIS>)> for ZipItems<'a, K, V, W, IS, WIS> {
+    fn next(&mut self) -> Option<(Option<&'a IS>, Option<&'a WIS>)> {
+        let order = match (self.next_left, self.next_right) {
+            (None, None) => return None,
+            (Some(_), None) => Less,
+            (None, Some(_)) => Greater,
+            (Some(left), Some(right)) => left.key().cmp(right.key())
+        };
+
+        let left = if order == Greater {
+            None
+        } else {
+            mem::replace(&mut self.next_left, self.left.next_item())
+        };
+        let right = if order == Less {
+            None
+        } else {
+            mem::replace(&mut self.next_right, self.right.next_item())
+        };
+
+        Some((left, right))
+    }
+}
+
+// Finds the gaps between adjacent entries. See `RedBlackTree::gaps()`.
+pub struct Gaps<'a, K, V, IS, G> {
+    entries: Entries<'a, K, V, IS>,
+    previous: Option<(&'a K, &'a V)>,
+    gap: |&K, &K|:'a -> Option<G>,
+}
+
+impl<'a, K: Ord+Clone+Send+Sync, V: Clone+Send+Sync, IS: ItemStore<K, V>, G>
+Iterator<(G, (&'a K, &'a V), (&'a K, &'a V))> for Gaps<'a, K, V, IS, G> {
+    fn next(&mut self) -> Option<(G, (&'a K, &'a V), (&'a K, &'a V))> {
+        loop {
+            let current = match self.entries.next() {
+                Some(entry) => entry,
+                None => return None
+            };
+
+            let previous = mem::replace(&mut self.previous, Some(current));
+            match previous {
+                Some(previous) => {
+                    match (self.gap)(previous.val0(), current.val0()) {
+                        Some(gap) => return Some((gap, previous, current)),
+                        None => {}
+                    }
+                }
+                None => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RedBlackTree, SortedBuilder, BoundedMap, LruMap, Publisher, CmpKey, Delta};
+    use super::{CmpCounter, CountingCmp, StepMap, TieBreakKey, Changes};
+    use super::{CopyAll, ShareUnchanged, Source, Left, Right, Both};
+    use super::{max_black_height_for};
+    use super::{Red, Black, KeyNotFound, DecodeError, Set, Remove, EvictSmallest, EvictLargest};
+    use std::collections::{TreeMap, HashSet};
+    use std::iter::range_step;
+    use std::rand::{Rng, StdRng};
+    use testing::Test;
+    use sync::Arc;
+    use test::Bencher;
+    use item_store::{ItemStore, CopyStore, ShareStore, InternedValueStore};
+
+    fn build(keys: &[u64]) -> RedBlackTree<u64, u64, CopyStore<u64, u64>> {
+        let mut tree = RedBlackTree::new();
+        for &key in keys.iter() {
+            tree = tree.insert(CopyStore::new(key, key)).val0();
+        }
+        tree
+    }
+
+    #[test]
+    fn test_runs() {
+        let tree = build([1, 2, 3, 5, 6, 10]);
+        let runs: Vec<Vec<u64>> = tree.runs(|a, b| *a + 1 == *b)
+                                      .map(|run| run.iter().map(|&(k, _)| *k).collect())
+                                      .collect();
+        assert_eq!(runs, vec![vec![1, 2, 3], vec![5, 6], vec![10]]);
+    }
+
+    #[test]
+    fn test_gaps() {
+        let tree = build([1, 2, 5, 6, 10]);
+        let gaps: Vec<(u64, u64, u64)> = tree.gaps(|a, b| if *b - *a > 1 { Some(*b - *a - 1) }
+                                                          else { None })
+                                             .map(|(gap, (a, _), (b, _))| (gap, *a, *b))
+                                             .collect();
+        assert_eq!(gaps, vec![(2, 2, 5), (3, 6, 10)]);
+
+        assert_eq!(build([1, 2, 3]).gaps(|a, b| if *b - *a > 1 { Some(()) } else { None })
+                                   .count(), 0);
+        assert_eq!(build([7]).gaps(|_, _| Some(())).count(), 0);
+    }
+
+    #[test]
+    fn test_find_or_err() {
+        let tree = build([1, 2, 3]);
+        assert_eq!(tree.find_or_err(&2), Ok(&2));
+        assert_eq!(tree.find_or_err(&4), Err(KeyNotFound));
+    }
+
+    #[test]
+    fn test_contains_key() {
+        let tree = build(range(0u64, 100).map(|x| x * 2).collect::<Vec<u64>>().as_slice());
+
+        for key in range(0u64, 200) {
+            assert_eq!(tree.contains_key(&key), key % 2 == 0);
+        }
+        assert!(!tree.contains_key(&1000));
+        assert!(!build([]).contains_key(&0));
+
+        let map: &Map<u64, u64> = &tree;
+        assert!(map.contains_key(&10));
+        assert!(!map.contains_key(&11));
+    }
+
+    #[test]
+    fn test_from_iter() {
+        let pairs = vec![(3u64, 30u64), (1, 10), (2, 20), (3, 31), (1, 11), (4, 40)];
+        let tree: RedBlackTree<u64, u64, CopyStore<u64, u64>> = pairs.move_iter().collect();
+
+        assert_eq!(tree.len(), 4);
+        assert_eq!(tree.find(&1), Some(&11));
+        assert_eq!(tree.find(&2), Some(&20));
+        assert_eq!(tree.find(&3), Some(&31));
+        assert_eq!(tree.find(&4), Some(&40));
+        assert_eq!(tree.find(&5), None);
+        assert!(tree.check_invariants());
+
+        let empty: RedBlackTree<u64, u64, CopyStore<u64, u64>> = Vec::new().move_iter().collect();
+        assert!(empty.is_empty());
+    }
+
+    #[test]
+    fn test_extend() {
+        let mut tree = build([1, 3, 5]);
+        let original = tree.clone();
+        tree.extend(vec![(2u64, 20u64), (3, 30), (6, 60)].move_iter());
+
+        assert_eq!(tree.len(), 5);
+        assert_eq!(tree.iter().map(|(k, v)| (*k, *v)).collect::<Vec<(u64, u64)>>(),
+                   vec![(1, 1), (2, 20), (3, 30), (5, 5), (6, 60)]);
+        assert!(tree.check_invariants());
+
+        // The tree we extended from is left untouched
+        assert_eq!(original.len(), 3);
+        assert_eq!(original.find(&3), Some(&3));
+    }
+
+    #[test]
+    fn test_show() {
+        let tree = build([5, 1, 3]).insert(CopyStore::new(4, 40)).val0();
+        assert_eq!(format!("{}", tree), "{1: 1, 3: 3, 4: 40, 5: 5}".to_string());
+        assert_eq!(format!("{}", build([])), "{}".to_string());
+    }
+
+    #[test]
+    fn test_eq_by_contents() {
+        let ascending = build([1, 2, 3, 4, 5, 6, 7, 8, 9, 10]);
+        let scrambled = build([7, 3, 10, 1, 9, 5, 2, 8, 4, 6]);
+        assert!(ascending == scrambled);
+        assert_eq!(ascending, scrambled);
+
+        let changed = scrambled.clone().insert(CopyStore::new(4, 40)).val0();
+        assert!(ascending != changed);
+        assert!(ascending != scrambled.remove(&10).val0());
+        assert!(build([]) == build([]));
+    }
+
+    #[test]
+    fn test_find_sequential_100k() {
+        let mut tree = build([]);
+        for key in range(0u64, 100000) {
+            tree = tree.insert(CopyStore::new(key, key + 1)).val0();
+        }
+
+        for &key in [0u64, 1, 4242, 50000, 65535, 99998, 99999].iter() {
+            assert_eq!(tree.find(&key), Some(&(key + 1)));
+        }
+        assert_eq!(tree.find(&100000), None);
+        assert_eq!(tree.find(&::std::u64::MAX), None);
+    }
+
+    #[test]
+    fn test_find_or() {
+        let tree = build([1, 2, 3]);
+        let default = 100u64;
+        assert_eq!(*tree.find_or(&2, &default), 2);
+        assert!(tree.find_or(&4, &default) as *const u64 == &default as *const u64);
+    }
+
+    #[test]
+    fn test_contains_all() {
+        let tree = build([2, 4, 6, 8, 10]);
+        let sorted = [0u64, 2, 3, 4, 4, 9, 10, 11];
+        let unsorted = [11u64, 4, 0, 10, 3, 2, 9, 4];
+
+        for keys in [sorted.as_slice(), unsorted.as_slice()].iter() {
+            let expected: Vec<bool> = keys.iter().map(|k| tree.contains_key(k)).collect();
+            assert_eq!(tree.contains_all(*keys), expected);
+        }
+    }
+
+    #[bench]
+    fn bench_contains_all_sorted_100000(bh: &mut Bencher) {
+        let tree = build(range(0u64, 100000).map(|x| x * 2).collect::<Vec<u64>>().as_slice());
+        let keys: Vec<u64> = range(0u64, 100000).collect();
+        bh.iter(|| tree.contains_all(keys.as_slice()));
+    }
+
+    #[bench]
+    fn bench_contains_all_unsorted_100000(bh: &mut Bencher) {
+        let tree = build(range(0u64, 100000).map(|x| x * 2).collect::<Vec<u64>>().as_slice());
+        let keys: Vec<u64> = range(0u64, 100000).rev().collect();
+        bh.iter(|| tree.contains_all(keys.as_slice()));
+    }
+
+    #[test]
+    fn test_eq_by() {
+        let mut a = RedBlackTree::<u64, f64, CopyStore<u64, f64>>::new();
+        let mut b = RedBlackTree::<u64, f64, CopyStore<u64, f64>>::new();
+        for i in range(0u64, 100) {
+            a = a.insert(CopyStore::new(i, i as f64)).val0();
+            b = b.insert(CopyStore::new(i, i as f64 + 0.0001)).val0();
+        }
+
+        fn close(x: &f64, y: &f64) -> bool { (*x - *y).abs() < 0.001 }
+        assert!(a.eq_by(&b, close));
+        assert!(!a.eq_by(&b, |x, y| *x == *y));
+        assert!(!a.eq_by(&b.clone().remove(&50).val0(), close));
+    }
+
+    #[test]
+    fn test_lru_map() {
+        let mut map = LruMap::<u64, u64, CopyStore<u64, u64>>::new(3);
+
+        assert_eq!(map.insert(CopyStore::new(1, 10)), None);
+        assert_eq!(map.insert(CopyStore::new(2, 20)), None);
+        assert_eq!(map.insert(CopyStore::new(3, 30)), None);
+
+        // Accessing 1 makes 2 the least recently used key
+        assert_eq!(map.get(&1), Some(&10));
+        assert_eq!(map.insert(CopyStore::new(4, 40)), Some((2, 20)));
+
+        // Overwriting 3 counts as an access, too
+        assert_eq!(map.insert(CopyStore::new(3, 31)), None);
+        assert_eq!(map.get(&5), None);
+        assert_eq!(map.insert(CopyStore::new(5, 50)), Some((1, 10)));
+
+        assert_eq!(map.len(), 3);
+        assert!(map.tree().check_invariants());
+        let keys: Vec<u64> = map.tree().iter().map(|(k, _)| *k).collect();
+        assert_eq!(keys, vec![3, 4, 5]);
+        assert_eq!(map.get(&3), Some(&31));
+    }
+
+    #[test]
+    fn test_bounded_map_evict_smallest() {
+        let mut map = BoundedMap::<u64, u64, CopyStore<u64, u64>>::new(3, EvictSmallest);
+        let mut evicted = Vec::new();
+
+        for &key in [5u64, 3, 8, 1, 9, 7].iter() {
+            let (map1, entry) = map.insert(CopyStore::new(key, key * 10));
+            evicted.extend(entry.move_iter());
+            map = map1;
+            assert!(map.len() <= 3);
+        }
+
+        assert_eq!(map.len(), 3);
+        assert_eq!(evicted, vec![(1, 10), (3, 30), (5, 50)]);
+        let keys: Vec<u64> = map.tree().iter().map(|(k, _)| *k).collect();
+        assert_eq!(keys, vec![7, 8, 9]);
+    }
+
+    #[test]
+    fn test_bounded_map_evict_largest() {
+        let mut map = BoundedMap::<u64, u64, CopyStore<u64, u64>>::new(2, EvictLargest);
+        let mut evicted = Vec::new();
+
+        for &key in [4u64, 2, 6, 1, 4].iter() {
+            let (map1, entry) = map.insert(CopyStore::new(key, key));
+            evicted.extend(entry.move_iter());
+            map = map1;
+        }
+
+        assert_eq!(map.len(), 2);
+        assert_eq!(evicted, vec![(6, 6), (4, 4), (4, 4)]);
+        assert_eq!(map.find(&1), Some(&1));
+        assert_eq!(map.find(&2), Some(&2));
+    }
+
+    #[test]
+    fn test_iter_random_keys() {
+        let mut rng = StdRng::new().ok().expect("Could not create random number generator");
+        let keys: Vec<u64> = range(0u, 1000).map(|_| rng.gen()).collect();
+        let tree = build(keys.as_slice());
+
+        let mut expected = keys.clone();
+        expected.sort();
+        expected.dedup();
+
+        let iterated: Vec<u64> = tree.iter().map(|(k, _)| *k).collect();
+        assert_eq!(iterated, expected);
+
+        // The values are references into the tree, not clones
+        assert!(tree.iter().all(|(k, v)| tree.find(k).unwrap() as *const u64 == v as *const u64));
+    }
+
+    #[test]
+    fn test_iter_empty() {
+        let tree = build([]);
+        let mut iter = tree.iter();
+        assert_eq!(iter.size_hint(), (0, Some(0)));
+        assert_eq!(iter.next(), None);
+        assert_eq!(iter.next(), None);
+    }
+
+    #[test]
+    fn test_iter_stack_stays_logarithmic() {
+        let count = 100000u;
+        let tree = build(range(0u64, count as u64).collect::<Vec<u64>>().as_slice());
+        let max_height = 2.0 * ((count + 1) as f64).log2();
+
+        let mut iter = tree.iter();
+        let mut peak = iter.stack.len();
+        let mut visited = 0u;
+
+        loop {
+            match iter.next() {
+                Some(_) => visited += 1,
+                None => break
+            }
+            peak = ::std::cmp::max(peak, iter.stack.len());
+        }
+
+        assert_eq!(visited, count);
+        assert!((peak as f64) <= max_height);
+    }
+
+    fn check_join(left_keys: &[u64], key: u64, right_keys: &[u64]) {
+        let left = build(left_keys);
+        let right = build(right_keys);
+
+        let joined = RedBlackTree::join(left, CopyStore::new(key, key), right);
+        assert!(joined.balanced());
+        assert!(joined.no_red_red());
+        assert_eq!(joined.len(), left_keys.len() + 1 + right_keys.len());
+
+        let mut expected = Vec::from_slice(left_keys);
+        expected.push(key);
+        expected.push_all(right_keys);
+        let actual: Vec<u64> = joined.iter().map(|(k, _)| *k).collect();
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn test_join() {
+        let big: Vec<u64> = range(0u64, 1000).collect();
+        let small: Vec<u64> = range(1001u64, 1004).collect();
+        check_join(big.as_slice(), 1000, small.as_slice());
+        assert!(build(big.as_slice()).root.black_height() >
+                build(small.as_slice()).root.black_height() + 1);
+
+        let small: Vec<u64> = range(0u64, 3).collect();
+        let big: Vec<u64> = range(4u64, 1004).collect();
+        check_join(small.as_slice(), 3, big.as_slice());
+
+        check_join([], 0, []);
+        check_join([], 0, big.as_slice());
+        check_join(big.as_slice(), 2000, []);
+        check_join([1, 2, 3], 4, [5, 6, 7]);
+    }
+
+    #[test]
+    fn test_from_trees_disjoint() {
+        let keys: Vec<u64> = range(0u64, 800).collect();
+        let original = build(keys.as_slice());
+        let shards: Vec<RedBlackTree<u64, u64, CopyStore<u64, u64>>> =
+            keys.as_slice().chunks(100).map(|chunk| build(chunk)).collect();
+        assert_eq!(shards.len(), 8);
+
+        let merged = RedBlackTree::from_trees(shards);
+        assert!(merged.balanced());
+        assert!(merged.no_red_red());
+        assert!(merged.eq_by(&original, |a, b| *a == *b));
+    }
+
+    #[test]
+    fn test_from_trees_overlapping() {
+        let a = build([1, 3, 5, 7]);
+        let b = build([2, 3, 4]).insert(CopyStore::new(5, 50)).val0();
+        let c = build([0, 7, 9]);
+
+        let merged = RedBlackTree::from_trees(vec![a, b, c]);
+        assert!(merged.balanced());
+        assert!(merged.no_red_red());
+        assert_eq!(merged.len(), 8);
+        let entries: Vec<(u64, u64)> = merged.iter().map(|(k, v)| (*k, *v)).collect();
+        assert_eq!(entries, vec![(0, 0), (1, 1), (2, 2), (3, 3), (4, 4),
+                                 (5, 5), (7, 7), (9, 9)]);
+    }
+
+    // A key that is ordered by `id` only, but only equal by `Eq` if the tags match, too. Its `tag`
+    // is shared between clones, so that tests can tell which key instance a tree holds.
+    #[deriving(Clone)]
+    struct TaggedKey {
+        id: u64,
+        tag: Arc<u64>,
+    }
+
+    fn tagged(id: u64, tag: u64) -> TaggedKey {
+        TaggedKey { id: id, tag: Arc::new(tag) }
+    }
+
+    impl PartialEq for TaggedKey {
+        fn eq(&self, other: &TaggedKey) -> bool { self.id == other.id && *self.tag == *other.tag }
+    }
+
+    impl Eq for TaggedKey {}
+
+    impl PartialOrd for TaggedKey {
+        fn partial_cmp(&self, other: &TaggedKey) -> Option<Ordering> { Some(self.cmp(other)) }
+    }
+
+    impl Ord for TaggedKey {
+        fn cmp(&self, other: &TaggedKey) -> Ordering { self.id.cmp(&other.id) }
+    }
+
+    #[test]
+    fn test_insert_ord_equal_keys() {
+        let tree = RedBlackTree::<TaggedKey, u64, CopyStore<TaggedKey, u64>>::new();
+        let (tree, new_entry1) = tree.insert(CopyStore::new(tagged(1, 0), 10));
+        let (tree, new_entry2) = tree.insert(CopyStore::new(tagged(1, 1), 20));
+
+        assert!(new_entry1);
+        assert!(!new_entry2);
+        assert_eq!(tree.len, 1);
+
+        // Lookups only go by `Ord`, and the latest insertion replaced both key and value
+        assert_eq!(tree.find(&tagged(1, 7)), Some(&20));
+        let entries: Vec<(u64, u64, u64)> = tree.iter().map(|(k, v)| (k.id, *k.tag, *v)).collect();
+        assert_eq!(entries, vec![(1, 1, 20)]);
+    }
+
+    #[test]
+    fn test_insert_interned() {
+        let stored = tagged(1, 0);
+        let tree = RedBlackTree::<TaggedKey, u64, CopyStore<TaggedKey, u64>>::new();
+        let (tree, new_entry1) = tree.insert_interned(stored.clone(), 10);
+        let (tree, new_entry2) = tree.insert_interned(tagged(1, 1), 20);
+        let (tree, new_entry3) = tree.insert_interned(tagged(2, 2), 30);
+
+        assert!(new_entry1);
+        assert!(!new_entry2);
+        assert!(new_entry3);
+        assert_eq!(tree.len, 2);
+
+        let entries: Vec<(u64, u64, u64)> = tree.iter().map(|(k, v)| (k.id, *k.tag, *v)).collect();
+        assert_eq!(entries, vec![(1, 0, 20), (2, 2, 30)]);
+
+        // The value-only update kept the very key instance that was inserted first
+        let (key, _) = tree.iter().next().unwrap();
+        assert!(&*key.tag as *const u64 == &*stored.tag as *const u64);
+
+        // A plain insert replaces the key instance as well
+        let (tree, _) = tree.insert(CopyStore::new(tagged(1, 3), 40));
+        let (key, _) = tree.iter().next().unwrap();
+        assert!(&*key.tag as *const u64 != &*stored.tag as *const u64);
+    }
+
+    #[test]
+    fn test_remove_iterative() {
+        let mut reference = TreeMap::<u64, u64>::new();
+        let mut tree = RedBlackTree::<u64, u64, CopyStore<u64, u64>>::new();
+        let mut rng = StdRng::new().ok().expect("Could not create random number generator");
+
+        for _ in range(0u, 20000) {
+            let key = rng.gen_range(0u64, 1000);
+
+            if rng.gen_weighted_bool(2) {
+                let recursive = tree.clone().remove(&key).val0();
+                let (tree1, removed) = tree.remove_iterative(&key);
+                assert_eq!(removed, reference.remove(&key));
+                assert!(tree1.root.structurally_eq(&recursive.root));
+                tree = tree1;
+            } else {
+                reference.insert(key, key);
+                tree = tree.insert(CopyStore::new(key, key)).val0();
+            }
+
+            assert_eq!(tree.len, reference.len());
+        }
+
+        assert!(tree.balanced());
+        assert!(tree.no_red_red());
+        let entries: Vec<(u64, u64)> = tree.iter().map(|(k, v)| (*k, *v)).collect();
+        let expected: Vec<(u64, u64)> = reference.iter().map(|(k, v)| (*k, *v)).collect();
+        assert_eq!(entries, expected);
+    }
+
+    #[bench]
+    fn bench_remove_recursive_50000(bh: &mut Bencher) {
+        let keys: Vec<u64> = range(0u64, 50000).map(|x| x * 7919 % 50000).collect();
+        let tree = build(keys.as_slice());
+
+        bh.iter(|| {
+            let mut tree = tree.clone();
+            for i in range_step(0, keys.len(), 2) {
+                tree = tree.remove(&keys[i]).val0();
+            }
+        })
+    }
+
+    #[bench]
+    fn bench_remove_iterative_50000(bh: &mut Bencher) {
+        let keys: Vec<u64> = range(0u64, 50000).map(|x| x * 7919 % 50000).collect();
+        let tree = build(keys.as_slice());
+
+        bh.iter(|| {
+            let mut tree = tree.clone();
+            for i in range_step(0, keys.len(), 2) {
+                tree = tree.remove_iterative(&keys[i]).val0();
+            }
+        })
+    }
+
+    #[deriving(Clone, PartialEq, Show)]
+    struct Record {
+        id: u64,
+        name: &'static str,
+    }
+
+    #[test]
+    fn test_by_key() {
+        let records = vec![Record { id: 3, name: "c" },
+                           Record { id: 1, name: "a" },
+                           Record { id: 2, name: "b" },
+                           Record { id: 1, name: "d" }];
+        let index = RedBlackTree::<u64, Record, CopyStore<u64, Record>>::by_key(
+            records.move_iter(), |record| record.id);
+
+        assert_eq!(index.len, 3);
+        assert_eq!(index.find(&1), Some(&Record { id: 1, name: "d" }));
+        assert_eq!(index.find(&2), Some(&Record { id: 2, name: "b" }));
+        assert_eq!(index.find(&3), Some(&Record { id: 3, name: "c" }));
+        assert_eq!(index.find(&4), None);
+    }
+
+    #[test]
+    fn test_insert_if_changed() {
+        let tree = build(range(0u64, 100).collect::<Vec<u64>>().as_slice());
+
+        let (same, new_entry) = tree.clone().insert_if_changed(CopyStore::new(42, 42));
+        assert!(!new_entry);
+        assert_eq!(same.root.node_ptr(), tree.root.node_ptr());
+
+        let (changed, new_entry) = tree.clone().insert_if_changed(CopyStore::new(42, 43));
+        assert!(!new_entry);
+        assert!(changed.root.node_ptr() != tree.root.node_ptr());
+        assert_eq!(changed.find(&42), Some(&43));
+
+        let (added, new_entry) = tree.clone().insert_if_changed(CopyStore::new(100, 100));
+        assert!(new_entry);
+        assert_eq!(added.len, 101);
+    }
+
+    #[test]
+    fn test_iter_with_rank() {
+        let keys: Vec<u64> = range(0u64, 1000).map(|x| x * 7919 % 1000).collect();
+        let tree = build(keys.as_slice());
+
+        let mut count = 0u;
+        for ((rank, key, _), (index, (expected_key, _))) in
+                tree.iter_with_rank().zip(tree.iter().enumerate()) {
+            assert_eq!(rank, index);
+            assert_eq!(key, expected_key);
+            assert_eq!(tree.select(rank), Some((key, key)));
+            assert_eq!(tree.rank(key), rank);
+            count += 1;
+        }
+
+        assert_eq!(count, tree.len);
+        assert_eq!(tree.select(tree.len), None);
+    }
+
+    #[test]
+    fn test_decode_entries_checked() {
+        for count in range(0u64, 100) {
+            let entries: Vec<(u64, u64)> = range(0, count).map(|x| (x * 2, x)).collect();
+            let tree = RedBlackTree::<u64, u64, CopyStore<u64, u64>>::decode_entries_checked(
+                entries.clone().move_iter()).unwrap();
+
+            assert!(tree.balanced());
+            assert!(tree.no_red_red());
+            assert_eq!(tree.len, count as uint);
+            let decoded: Vec<(u64, u64)> = tree.iter().map(|(k, v)| (*k, *v)).collect();
+            assert_eq!(decoded, entries);
+
+            let unchecked = RedBlackTree::<u64, u64, CopyStore<u64, u64>>::decode_entries_unchecked(
+                entries.clone().move_iter());
+            assert!(unchecked.root.structurally_eq(&tree.root));
+        }
+    }
+
+    #[test]
+    fn test_decode_entries_checked_unsorted() {
+        let out_of_order = vec![(1u64, 1u64), (3, 3), (2, 2), (4, 4)];
+        let duplicate = vec![(1u64, 1u64), (2, 2), (2, 2)];
+
+        assert_eq!(RedBlackTree::<u64, u64, CopyStore<u64, u64>>::decode_entries_checked(
+            out_of_order.move_iter()).err(), Some(DecodeError));
+        assert_eq!(RedBlackTree::<u64, u64, CopyStore<u64, u64>>::decode_entries_checked(
+            duplicate.move_iter()).err(), Some(DecodeError));
+    }
+
+    #[test]
+    fn test_range() {
+        let tree = build(range(0u64, 100).map(|x| x * 2).collect::<Vec<u64>>().as_slice());
+
+        let keys: Vec<u64> = tree.range(&15, &31).map(|(k, _)| *k).collect();
+        assert_eq!(keys, vec![16, 18, 20, 22, 24, 26, 28, 30]);
+        assert_eq!(tree.range(&50, &50).count(), 0);
+        assert_eq!(tree.range(&500, &600).count(), 0);
+        assert_eq!(tree.range(&0, &1000).count(), 100);
+    }
+
+    #[test]
+    fn test_subtree_clone() {
+        let tree = build(range(0u64, 100).collect::<Vec<u64>>().as_slice());
+        let sub = tree.subtree_clone(&20, &40);
+
+        assert!(sub.balanced());
+        assert!(sub.no_red_red());
+        assert_eq!(sub.len, 20);
+        let keys: Vec<u64> = sub.iter().map(|(k, _)| *k).collect();
+        assert_eq!(keys, range(20u64, 40).collect());
+
+        let (sub, _) = sub.insert(CopyStore::new(30, 300));
+        let (sub, _) = sub.remove(&21);
+        let (sub, _) = sub.insert(CopyStore::new(1000, 1000));
+        assert_eq!(sub.find(&30), Some(&300));
+        assert_eq!(tree.find(&30), Some(&30));
+        assert_eq!(tree.find(&21), Some(&21));
+        assert_eq!(tree.find(&1000), None);
+        assert_eq!(tree.len, 100);
+    }
+
+    fn build_from_sorted(keys: &[u64]) -> RedBlackTree<u64, u64, CopyStore<u64, u64>> {
+        RedBlackTree::from_sorted(keys.iter().map(|&k| CopyStore::new(k, k)).collect())
+    }
+
+    #[test]
+    fn test_find_with_depth() {
+        //        3
+        //    1       5
+        //  0   2   4   6
+        let tree = build_from_sorted([0, 1, 2, 3, 4, 5, 6]);
+
+        assert_eq!(tree.find_with_depth(&3), (Some(&3), 1));
+        assert_eq!(tree.find_with_depth(&1), (Some(&1), 2));
+        assert_eq!(tree.find_with_depth(&5), (Some(&5), 2));
+        assert_eq!(tree.find_with_depth(&0), (Some(&0), 3));
+        assert_eq!(tree.find_with_depth(&6), (Some(&6), 3));
+        assert_eq!(tree.find_with_depth(&7), (None, 3));
+
+        let empty = build_from_sorted([]);
+        assert_eq!(empty.find_with_depth(&0), (None, 0));
+    }
+
+    #[test]
+    fn test_apply_delta() {
+        let base = build([1, 2, 3, 4, 5]);
+        let mut delta = RedBlackTree::<u64, Delta<u64>, CopyStore<u64, Delta<u64>>>::new();
+        delta = delta.insert(CopyStore::new(0, Set(100))).val0();
+        delta = delta.insert(CopyStore::new(2, Remove)).val0();
+        delta = delta.insert(CopyStore::new(4, Set(40))).val0();
+        delta = delta.insert(CopyStore::new(7, Remove)).val0();
+        delta = delta.insert(CopyStore::new(9, Set(90))).val0();
+
+        let result = base.clone().apply_delta(&delta);
+        assert!(result.balanced());
+        assert!(result.no_red_red());
+        let entries: Vec<(u64, u64)> = result.iter().map(|(k, v)| (*k, *v)).collect();
+        assert_eq!(entries, vec![(0, 100), (1, 1), (3, 3), (4, 40), (5, 5), (9, 90)]);
+        assert_eq!(result.len, 6);
+        assert_eq!(base.len, 5);
+    }
+
+    #[test]
+    fn test_diff() {
+        let old = build([1, 2, 3, 4]);
+        let new = old.clone().remove(&2).val0()
+                         .insert(CopyStore::new(4, 40)).val0()
+                         .insert(CopyStore::new(5, 5)).val0();
+
+        assert_eq!(old.diff(&new), Changes {
+            added: vec![(5, 5)],
+            removed: vec![2],
+            changed: vec![(4, 40)]
+        });
+        assert_eq!(old.diff(&old), Changes { added: vec![], removed: vec![], changed: vec![] });
+    }
+
+    #[test]
+    fn test_apply_changes_roundtrip() {
+        let mut rng = StdRng::new().ok().expect("Could not create random number generator");
+
+        for _ in range(0u, 50) {
+            let mut old = build([]);
+            let mut new = build([]);
+
+            for _ in range(0u, rng.gen_range(0u, 200)) {
+                let key = rng.gen_range(0u64, 300);
+                old = old.insert(CopyStore::new(key, rng.gen_range(0u64, 3))).val0();
+            }
+            for _ in range(0u, rng.gen_range(0u, 200)) {
+                let key = rng.gen_range(0u64, 300);
+                new = new.insert(CopyStore::new(key, rng.gen_range(0u64, 3))).val0();
+            }
+
+            let changes = old.diff(&new);
+            let applied = old.clone().apply_changes(&changes);
+            assert!(applied.check_invariants());
+            assert!(applied.eq_by(&new, |a, b| *a == *b));
+        }
+    }
+
+    #[test]
+    fn test_positional() {
+        let keys: Vec<u64> = range(0u64, 200).map(|x| x * 7919 % 1000).collect();
+        let tree = build(keys.as_slice());
+        let mut sorted = keys.clone();
+        sorted.sort();
+
+        let view = tree.positional();
+        assert_eq!(view.len(), sorted.len());
+
+        for (i, key) in sorted.iter().enumerate() {
+            assert_eq!(view.get(i), Some((key, key)));
+            assert_eq!(view.binary_search(key), Ok(i));
+            assert_eq!(view.binary_search(&(*key + 1000)), Err(sorted.len()));
+        }
+
+        assert_eq!(view.get(sorted.len()), None);
+        for probe in range(0u64, 1000) {
+            let position = sorted.iter().take_while(|k| **k < probe).count();
+            match view.binary_search(&probe) {
+                Ok(index) => { assert_eq!(index, position); assert_eq!(sorted[index], probe); }
+                Err(index) => { assert_eq!(index, position); assert!(!sorted.contains(&probe)); }
+            }
+        }
+    }
+
+    #[test]
+    fn test_remove_by_value() {
+        let mut tree = RedBlackTree::<u64, u64, CopyStore<u64, u64>>::new();
+        for key in range(0u64, 100) {
+            tree = tree.insert(CopyStore::new(key, key * 37 % 100)).val0();
+        }
+
+        let (survivors, removed) = tree.clone().remove_by_value(|v| *v < 30);
+        assert_eq!(removed, 30);
+        assert_eq!(survivors.len, 70);
+        assert!(survivors.balanced());
+        assert!(survivors.no_red_red());
+        assert!(survivors.iter().all(|(k, v)| *v >= 30 && tree.find(k) == Some(v)));
+
+        let (unchanged, removed) = tree.clone().remove_by_value(|_| false);
+        assert_eq!(removed, 0);
+        assert!(unchanged.eq_by(&tree, |a, b| *a == *b));
+    }
+
+    #[test]
+    fn test_new_does_not_allocate() {
+        // Nodes are the only heap allocations made by a tree, and leaves don't have any
+        let empty = RedBlackTree::<u64, u64, CopyStore<u64, u64>>::new();
+        assert!(empty.root.is_leaf());
+        assert_eq!(empty.root.node_ptr(), None);
+        assert!(empty.clone().root.is_leaf());
+
+        let emptied = build([1]).remove(&1).val0();
+        assert!(emptied.root.is_leaf());
+        assert_eq!(emptied.root.node_ptr(), None);
+    }
+
+    #[test]
+    fn test_sharing_report() {
+        let count = 1000u;
+        let mut versions = vec![build(range(0u64, count as u64).map(|x| x * 2).collect::<Vec<u64>>().as_slice())];
+        let initial = RedBlackTree::sharing_report(versions.as_slice());
+        assert_eq!(initial.distinct_nodes, count);
+        assert_eq!(initial.logical_nodes, count);
+
+        let max_new_nodes = (4.0 * ((2 * count + 1) as f64).log2()) as uint + 4;
+        let mut previous = initial;
+
+        for i in range(0u64, 100) {
+            let next = versions.last().unwrap().clone().insert(CopyStore::new(i * 20 + 1, i)).val0();
+            versions.push(next);
+
+            let report = RedBlackTree::sharing_report(versions.as_slice());
+            assert!(report.distinct_nodes > previous.distinct_nodes);
+            assert!(report.distinct_nodes - previous.distinct_nodes <= max_new_nodes);
+            previous = report;
+        }
+
+        assert!(previous.sharing_ratio() > 10.0);
+    }
+
+    #[test]
+    fn test_entry_owned_vacant() {
+        let keys: Vec<u64> = range(0u64, 100).map(|x| x * 2).collect();
+        let tree = build(keys.as_slice());
+
+        let entry = tree.entry_owned(51);
+        assert!(entry.is_vacant());
+        assert_eq!(entry.get(), None);
+
+        // Unrelated reads and a new version of the tree don't affect the entry
+        assert_eq!(tree.find(&50), Some(&50));
+        let other = tree.clone().remove(&50).val0();
+        assert_eq!(other.find(&50), None);
+
+        let result = entry.insert(510);
+        assert!(result.balanced());
+        assert!(result.no_red_red());
+        assert_eq!(result.len, 101);
+        assert_eq!(result.find(&51), Some(&510));
+        assert_eq!(result.find(&50), Some(&50));
+        assert!(keys.iter().all(|k| result.find(k) == Some(k)));
+        assert_eq!(tree.find(&51), None);
+    }
+
+    #[test]
+    fn test_entry_owned_occupied() {
+        let tree = build(range(0u64, 100).collect::<Vec<u64>>().as_slice());
+
+        let entry = tree.entry_owned(42);
+        assert!(!entry.is_vacant());
+        assert_eq!(entry.get(), Some(&42));
+
+        let result = entry.insert(420);
+        assert!(result.balanced());
+        assert!(result.no_red_red());
+        assert_eq!(result.len, 100);
+        assert_eq!(result.find(&42), Some(&420));
+        assert_eq!(tree.find(&42), Some(&42));
+    }
+
+    #[test]
+    fn test_structurally_eq() {
+        let keys: Vec<u64> = range(0u64, 100).collect();
+        let descending: Vec<u64> = keys.iter().rev().map(|k| *k).collect();
+
+        let sorted1 = build_from_sorted(keys.as_slice());
+        let sorted2 = build_from_sorted(keys.as_slice());
+        assert!(sorted1.structurally_eq(&sorted2));
+
+        let inserted1 = build(keys.as_slice());
+        let inserted2 = build(descending.as_slice());
+        assert!(inserted1.eq_by(&inserted2, |a, b| *a == *b));
+        assert!(!inserted1.structurally_eq(&inserted2));
+        assert!(inserted1.structurally_eq(&build(keys.as_slice())));
+    }
+
+    #[test]
+    fn test_neighbors() {
+        let tree = build(range(1u64, 50).map(|x| x * 10).collect::<Vec<u64>>().as_slice());
+
+        for probe in range(0u64, 520) {
+            let (predecessor, successor) = tree.neighbors(&probe);
+            assert_eq!(predecessor, tree.predecessor(&probe));
+            assert_eq!(successor, tree.successor(&probe));
+        }
+
+        assert_eq!(tree.neighbors(&5), (None, Some((&10, &10))));
+        assert_eq!(tree.neighbors(&10), (None, Some((&20, &20))));
+        assert_eq!(tree.neighbors(&250), (Some((&240, &240)), Some((&260, &260))));
+        assert_eq!(tree.neighbors(&255), (Some((&250, &250)), Some((&260, &260))));
+        assert_eq!(tree.neighbors(&490), (Some((&480, &480)), None));
+        assert_eq!(tree.neighbors(&1000), (Some((&490, &490)), None));
+        assert_eq!(build([]).neighbors(&1), (None, None));
+    }
+
+    #[test]
+    fn test_max_black_height_for() {
+        assert_eq!(max_black_height_for(0), 0);
+        assert_eq!(max_black_height_for(1), 1);
+        assert_eq!(max_black_height_for(2), 1);
+        assert_eq!(max_black_height_for(3), 2);
+        assert_eq!(max_black_height_for(6), 2);
+        assert_eq!(max_black_height_for(7), 3);
+        assert_eq!(max_black_height_for(1000000), 19);
+    }
+
+    #[test]
+    fn test_black_height_bound_large() {
+        let count = 1000000u;
+        let bulk = RedBlackTree::<u64, u64, CopyStore<u64, u64>>::decode_entries_unchecked(
+            range(0u64, count as u64).map(|x| (x, x)));
+        assert!(bulk.balanced());
+        assert!(bulk.black_height() <= max_black_height_for(bulk.len));
+
+        let mut inserted = RedBlackTree::<u64, u64, CopyStore<u64, u64>>::new();
+        for i in range(0u64, count as u64 / 5) {
+            inserted = inserted.insert(CopyStore::new(i * 7919 % count as u64, i)).val0();
+            if i % 10000 == 0 {
+                assert!(inserted.black_height() <= max_black_height_for(inserted.len));
+            }
+        }
+        assert!(inserted.balanced());
+        assert!(inserted.black_height() <= max_black_height_for(inserted.len));
+    }
+
+    struct Holder {
+        tree: RedBlackTree<u64, u64, CopyStore<u64, u64>>,
+    }
+
+    #[test]
+    fn test_merge_in_place() {
+        let mut holder = Holder { tree: build([1, 2, 3, 4]) };
+        let other = build([3, 4, 5, 6]);
+
+        holder.tree.merge_in_place(other, |_, a, b| *a + *b);
+
+        assert_eq!(holder.tree.len, 6);
+        assert!(holder.tree.balanced());
+        assert!(holder.tree.no_red_red());
+        let entries: Vec<(u64, u64)> = holder.tree.iter().map(|(k, v)| (*k, *v)).collect();
+        assert_eq!(entries, vec![(1, 1), (2, 2), (3, 6), (4, 8), (5, 5), (6, 6)]);
+    }
+
+    #[test]
+    fn test_sorted_builder() {
+        let count = 100000u;
+        let mut builder = SortedBuilder::<u64, u64, CopyStore<u64, u64>>::new();
+        let mut peak = 0u;
+
+        for key in range(0u64, count as u64) {
+            builder.push(key, key * 2);
+            peak = ::std::cmp::max(peak, builder.pending.len());
+        }
+
+        let tree = builder.finish();
+        let expected = RedBlackTree::<u64, u64, CopyStore<u64, u64>>::decode_entries_unchecked(
+            range(0u64, count as u64).map(|x| (x, x * 2)));
+
+        assert!(tree.balanced());
+        assert!(tree.no_red_red());
+        assert_eq!(tree.len, count);
+        assert!(tree.eq_by(&expected, |a, b| *a == *b));
+        assert!(peak as f64 <= ((count + 1) as f64).log2() + 1.0);
+
+        for n in range(0u64, 40) {
+            let mut builder = SortedBuilder::<u64, u64, CopyStore<u64, u64>>::new();
+            for key in range(0u64, n) {
+                builder.push(key, key);
+            }
+            let tree = builder.finish();
+            assert!(tree.balanced());
+            assert!(tree.no_red_red());
+            assert!(tree.eq_by(&build(range(0u64, n).collect::<Vec<u64>>().as_slice()),
+                               |a, b| *a == *b));
+        }
+    }
+
+    #[test]
+    #[should_fail]
+    fn test_sorted_builder_unsorted() {
+        let mut builder = SortedBuilder::<u64, u64, CopyStore<u64, u64>>::new();
+        builder.push(2, 2);
+        builder.push(1, 1);
+    }
+
+    #[test]
+    fn test_remove_leaves_black_root() {
+        let single = build([1]);
+        let (empty, _) = single.clone().remove(&1);
+        assert!(empty.root.is_leaf());
+        assert!(empty.root.col == Black);
+        let (empty, _) = single.remove_iterative(&1);
+        assert!(empty.root.is_leaf());
+        assert!(empty.root.col == Black);
+
+        for &(keys, removed) in [([1u64, 2], 1u64), ([1, 2], 2), ([2, 1], 1), ([2, 1], 2)].iter() {
+            let tree = build(keys.as_slice());
+            for result in [tree.clone().remove(&removed).val0(),
+                           tree.clone().remove_iterative(&removed).val0()].iter() {
+                assert!(!result.root.is_leaf());
+                assert!(result.root.col == Black);
+                assert_eq!(result.len, 1);
+                assert!(result.balanced());
+            }
+        }
+
+        let mut tree = build(range(0u64, 100).collect::<Vec<u64>>().as_slice());
+        for key in range(0u64, 100) {
+            tree = tree.remove(&((key * 37) % 100)).val0();
+            assert!(tree.root.col == Black);
+        }
+        assert!(tree.root.is_leaf());
+    }
+
+    #[test]
+    fn test_find_cow() {
+        let tree = build([1, 2, 3]);
+
+        let value = tree.find_cow(&2).unwrap();
+        assert!(value.is_borrowed());
+        assert_eq!(*value, 2);
+        assert_eq!(&*value as *const u64, tree.find(&2).unwrap() as *const u64);
+
+        let owned = value.into_owned();
+        assert_eq!(owned, 2);
+        assert!(&owned as *const u64 != tree.find(&2).unwrap() as *const u64);
+
+        assert!(tree.find_cow(&4).is_none());
+    }
+
+    #[test]
+    fn test_reorder_by() {
+        fn descending(a: &u64, b: &u64) -> Ordering { b.cmp(a) }
+
+        let tree = build(range(0u64, 100).collect::<Vec<u64>>().as_slice());
+        let reordered: RedBlackTree<CmpKey<u64>, u64, CopyStore<CmpKey<u64>, u64>> =
+            tree.reorder_by(descending);
+
+        assert!(reordered.balanced());
+        assert!(reordered.no_red_red());
+        assert_eq!(reordered.len, 100);
+        let keys: Vec<u64> = reordered.iter().map(|(k, _)| k.key).collect();
+        assert_eq!(keys, range(0u64, 100).rev().collect());
+
+        // Subsequent operations use the new order as well
+        let (reordered, _) = reordered.insert(CopyStore::new(CmpKey::new(1000, descending), 1));
+        let (reordered, _) = reordered.remove(&CmpKey::new(50, descending));
+        assert_eq!(reordered.iter().next().map(|(k, _)| k.key), Some(1000));
+        assert_eq!(reordered.find(&CmpKey::new(40, descending)), Some(&40));
+        assert_eq!(reordered.find(&CmpKey::new(50, descending)), None);
+    }
+
+    #[test]
+    fn test_counting_cmp() {
+        let counter = CmpCounter::new();
+        let count = 1000u;
+        let mut tree: RedBlackTree<CountingCmp<u64>, u64, CopyStore<CountingCmp<u64>, u64>> =
+            RedBlackTree::new();
+
+        for x in range(0u, count) {
+            let key = ((x * 7919) % count) as u64;
+            tree = tree.insert(CopyStore::new(counter.key(key), key)).val0();
+        }
+
+        // Every insert walks at most 2 * log2(n + 1) levels, so allow a few comparisons per level
+        let log2 = 10u;
+        assert!(counter.count() > 0);
+        assert!(counter.count() <= 4 * count * log2);
+        assert!(tree.check_invariants());
+
+        counter.reset();
+        assert_eq!(tree.find(&counter.key(500)), Some(&500));
+        assert!(counter.count() > 0);
+        assert!(counter.count() <= 2 * 2 * log2);
+    }
+
+    #[test]
+    fn test_tie_break_key() {
+        #[deriving(Clone)]
+        struct Event {
+            time: u64,
+            seq: uint,
+        }
+
+        fn by_time(a: &Event, b: &Event) -> Ordering { a.time.cmp(&b.time) }
+        fn by_seq(a: &Event, b: &Event) -> Ordering { a.seq.cmp(&b.seq) }
+
+        let events = [(30u64, 3u), (10, 0), (30, 1), (20, 5), (10, 2), (30, 2)];
+        let mut tree: RedBlackTree<TieBreakKey<Event>, uint, ShareStore<TieBreakKey<Event>, uint>> =
+            RedBlackTree::new();
+
+        for &(time, seq) in events.iter() {
+            let key = TieBreakKey::new(Event { time: time, seq: seq }, by_time, by_seq);
+            tree = tree.insert(ShareStore::new(key, seq)).val0();
+        }
+
+        assert_eq!(tree.len, 6);
+        assert!(tree.check_invariants());
+
+        let order: Vec<(u64, uint)> = tree.iter().map(|(k, _)| (k.key.time, k.key.seq)).collect();
+        assert_eq!(order, vec![(10, 0), (10, 2), (20, 5), (30, 1), (30, 2), (30, 3)]);
+
+        let probe = TieBreakKey::new(Event { time: 30, seq: 2 }, by_time, by_seq);
+        assert_eq!(tree.find(&probe), Some(&2));
+    }
+
+    #[test]
+    fn test_split_by() {
+        type Name = &'static str;
+        type People = RedBlackTree<Name, &'static str, CopyStore<Name, &'static str>>;
+
+        let staff = [("ada", "research"), ("bob", "sales"), ("cy", "research"), ("di", "ops"),
+                     ("ed", "sales"), ("flo", "research")];
+        let mut people: People = RedBlackTree::new();
+        for &(name, department) in staff.iter() {
+            people = people.insert(CopyStore::new(name, department)).val0();
+        }
+
+        let departments: RedBlackTree<&'static str, People, ShareStore<&'static str, People>> =
+            people.split_by(|department| *department);
+
+        assert_eq!(departments.len, 3);
+        assert!(departments.check_invariants());
+
+        let members = |department: &'static str| -> Vec<&'static str> {
+            let group = departments.find(&department).unwrap();
+            assert!(group.check_invariants());
+            group.iter().map(|(name, _)| *name).collect()
+        };
+
+        assert_eq!(members("ops"), vec!["di"]);
+        assert_eq!(members("research"), vec!["ada", "cy", "flo"]);
+        assert_eq!(members("sales"), vec!["bob", "ed"]);
+    }
+
+    #[test]
+    fn test_update_many() {
+        let tree = build(range(0u64, 1000).collect::<Vec<u64>>().as_slice());
+        let updated_keys: Vec<u64> = range(0u64, 50).map(|x| x * 20 + 3).collect();
+
+        let updates = updated_keys.iter().map(|&k| (k, k + 10000))
+                                  .chain(range(2000u64, 2010).map(|k| (k, k)));
+        let updated = tree.clone().update_many(updates);
+
+        assert!(updated.balanced());
+        assert!(updated.no_red_red());
+        assert!(updated.structurally_eq(&tree));
+        assert_eq!(updated.len, 1000);
+
+        for (key, val) in updated.iter() {
+            if updated_keys.contains(key) {
+                assert_eq!(*val, *key + 10000);
+            } else {
+                assert_eq!(*val, *key);
+            }
+        }
+        assert_eq!(updated.find(&2005), None);
+
+        // Unsorted updates and repeated keys
+        let updated = tree.clone().update_many(vec![(7u64, 1u64), (3, 2), (7, 3)].move_iter());
+        assert_eq!(updated.find(&3), Some(&2));
+        assert_eq!(updated.find(&7), Some(&3));
+        assert_eq!(tree.find(&7), Some(&7));
+
+        // Absent keys alone leave every node shared
+        let absent = range(0u64, 1000).map(|k| (k * 2 + 1001, k)).collect::<Vec<(u64, u64)>>();
+        let unchanged = tree.clone().update_many(absent.move_iter());
+        assert_eq!(unchanged.root.node_ptr(), tree.root.node_ptr());
+    }
+
+    #[test]
+    fn test_entries_ref() {
+        let keys: Vec<u64> = range(0u64, 100).map(|x| x * 7919 % 100).collect();
+        let tree = build(keys.as_slice());
+        let entries = tree.entries_ref();
+
+        assert_eq!(entries.len(), 100);
+        assert_eq!(entries.capacity(), 100);
+        assert!(entries.windows(2).all(|pair| *pair[0].val0() < *pair[1].val0()));
+
+        for &(key, val) in entries.iter() {
+            assert_eq!(val as *const u64, tree.find(key).unwrap() as *const u64);
+        }
+    }
+
+    #[test]
+    fn test_publisher() {
+        let publisher = Publisher::new(RedBlackTree::<u64, u64, CopyStore<u64, u64>>::new());
+        let (done_sender, done_receiver) = channel();
+        let reader_count = 8u;
+
+        for _ in range(0u, reader_count) {
+            let reader = publisher.reader();
+            let done_sender = done_sender.clone();
+
+            spawn(proc() {
+                let mut last_len = 0u;
+                while last_len < 1000 {
+                    let snapshot = reader.load();
+                    // Versions only ever grow, and each one contains exactly the keys [0, len)
+                    assert!(snapshot.len >= last_len);
+                    assert!(snapshot.iter().enumerate().all(|(i, (k, _))| *k == i as u64));
+                    last_len = snapshot.len;
+                }
+                done_sender.send(());
+            });
+        }
+
+        let mut tree = RedBlackTree::new();
+        for key in range(0u64, 1000) {
+            tree = tree.insert(CopyStore::new(key, key)).val0();
+            publisher.publish(tree.clone());
+        }
+
+        for _ in range(0u, reader_count) {
+            done_receiver.recv();
+        }
+
+        assert_eq!(publisher.reader().load().len, 1000);
+    }
+
+    #[test]
+    fn test_longest_run() {
+        let tree = build([1, 2, 3, 7, 8, 9, 10, 15]);
+        assert_eq!(tree.longest_run(|a, b| *a + 1 == *b), Some((4, (&7, &7), (&10, &10))));
+
+        let tied = build([1, 2, 4, 5, 9]);
+        assert_eq!(tied.longest_run(|a, b| *a + 1 == *b), Some((2, (&1, &1), (&2, &2))));
+
+        let single = build([5]);
+        assert_eq!(single.longest_run(|a, b| *a + 1 == *b), Some((1, (&5, &5), (&5, &5))));
+        assert_eq!(build([]).longest_run(|a, b| *a + 1 == *b), None);
+    }
+
+    // Builds a tree from several shuffles of `keys` and asserts that the results are valid and
+    // contain the same entries, no matter the order the keys were given in
+    pub fn assert_build_order_independent<K: Ord+Clone+Send+Sync,
+                                          V: PartialEq+Clone+Send+Sync,
+                                          IS: ItemStore<K, V>>(
+                                              keys: &[K],
+                                              build: |&[K]| -> RedBlackTree<K, V, IS>) {
+        let mut rng = StdRng::new().ok().expect("Could not create random number generator");
+        let mut shuffled = Vec::from_slice(keys);
+        let reference = build(keys);
+        assert!(reference.check_invariants());
+
+        for _ in range(0u, 5) {
+            rng.shuffle(shuffled.as_mut_slice());
+            let tree = build(shuffled.as_slice());
+            assert!(tree.check_invariants());
+            assert!(tree.eq_by(&reference, |a, b| *a == *b));
+        }
+    }
+
+    #[test]
+    fn test_build_order_independent() {
+        let mut rng = StdRng::new().ok().expect("Could not create random number generator");
+        let keys: Vec<u64> = range(0u, 300).map(|_| rng.gen_range(0u64, 10000)).collect();
+
+        assert_build_order_independent(keys.as_slice(), |keys| build(keys));
+        assert_build_order_independent(keys.as_slice(), |keys| {
+            let mut tree = RedBlackTree::<u64, u64, ShareStore<u64, u64>>::new();
+            for &key in keys.iter() {
+                tree = tree.insert(ShareStore::new(key, key * 2)).val0();
+            }
+            tree
+        });
+    }
+
+    #[test]
+    fn test_find_with_extremes() {
+        let tree = build(range(0u64, 100).map(|x| x * 7919 % 100).collect::<Vec<u64>>().as_slice());
+
+        assert_eq!(tree.find_with_extremes(&0), Some((&0, true, false)));
+        assert_eq!(tree.find_with_extremes(&99), Some((&99, false, true)));
+        assert_eq!(tree.find_with_extremes(&50), Some((&50, false, false)));
+        assert_eq!(tree.find_with_extremes(&100), None);
+
+        for key in range(1u64, 99) {
+            assert_eq!(tree.find_with_extremes(&key), Some((&key, false, false)));
+        }
+
+        let single = build([7]);
+        assert_eq!(single.find_with_extremes(&7), Some((&7, true, true)));
+    }
+
+    #[test]
+    fn test_symmetric_difference() {
+        let left = build(range(0u64, 60).collect::<Vec<u64>>().as_slice());
+        let right = build(range(40u64, 100).collect::<Vec<u64>>().as_slice())
+                        .insert(CopyStore::new(50, 500)).val0();
+
+        let result = left.clone().symmetric_difference(right.clone());
+        assert!(result.check_invariants());
+        assert_eq!(result.len, 80);
+        let keys: Vec<u64> = result.iter().map(|(k, _)| *k).collect();
+        let expected: Vec<u64> = range(0u64, 40).chain(range(60u64, 100)).collect();
+        assert_eq!(keys, expected);
+
+        assert_eq!(left.clone().symmetric_difference(left.clone()).len, 0);
+        assert!(left.clone().symmetric_difference(build([])).eq_by(&left, |a, b| *a == *b));
+    }
+
+    #[test]
+    fn test_median() {
+        assert_eq!(build([]).median(), None);
+        assert_eq!(build([4]).median(), Some((&4, &4)));
+        assert_eq!(build([4, 9]).median(), Some((&4, &4)));
+        assert_eq!(build([9, 1, 5, 3, 7]).median(), Some((&5, &5)));
+        assert_eq!(build([9, 1, 5, 3, 7, 11]).median(), Some((&5, &5)));
+
+        for len in range(1u, 200) {
+            let keys: Vec<u64> = range(0u64, len as u64).map(|x| x * 7919 % 1000).collect();
+            let mut sorted = keys.clone();
+            sorted.sort();
+            let median = sorted[(len - 1) / 2];
+            assert_eq!(build(keys.as_slice()).median(), Some((&median, &median)));
+        }
+    }
+
+    #[test]
+    fn test_top_k() {
+        let keys: Vec<u64> = range(0u64, 1000).map(|x| x * 7919 % 1000).collect();
+        let tree = build(keys.as_slice());
+
+        let top: Vec<u64> = tree.top_k(10).iter().map(|&(k, _)| *k).collect();
+        assert_eq!(top, range(990u64, 1000).rev().collect());
+
+        // The traversal stops once it has found enough entries
+        let mut items = Vec::new();
+        let visited = tree.root.collect_largest(10, &mut items);
+        assert_eq!(items.len(), 10);
+        assert!(visited <= 10 + 2 * max_black_height_for(1000) * 2);
+
+        assert_eq!(tree.top_k(0).len(), 0);
+        assert_eq!(build([]).top_k(5).len(), 0);
+
+        let all: Vec<u64> = tree.top_k(2000).iter().map(|&(k, _)| *k).collect();
+        assert_eq!(all, range(0u64, 1000).rev().collect());
+    }
+
+    #[test]
+    fn test_bottom_k() {
+        let keys: Vec<u64> = range(0u64, 1000).map(|x| x * 7919 % 1000).collect();
+        let tree = build(keys.as_slice());
+
+        let bottom: Vec<u64> = tree.bottom_k(10).iter().map(|&(k, _)| *k).collect();
+        assert_eq!(bottom, range(0u64, 10).collect());
+
+        let mut items = Vec::new();
+        let visited = tree.root.collect_smallest(10, &mut items);
+        assert_eq!(items.len(), 10);
+        assert!(visited <= 10 + 2 * max_black_height_for(1000) * 2);
+
+        assert_eq!(tree.bottom_k(0).len(), 0);
+        assert_eq!(build([]).bottom_k(5).len(), 0);
+
+        let all: Vec<u64> = tree.bottom_k(2000).iter().map(|&(k, _)| *k).collect();
+        assert_eq!(all, range(0u64, 1000).collect());
+    }
+
+    #[test]
+    fn test_extract_range() {
+        let tree = build(range(0u64, 100).collect::<Vec<u64>>().as_slice());
+
+        let (remaining, extracted) = tree.clone().extract_range(&30, &60);
+        assert!(remaining.check_invariants());
+        assert!(extracted.check_invariants());
+        assert_eq!(remaining.len + extracted.len, tree.len);
+        assert_eq!(extracted.keys_vec(), range(30u64, 60).collect());
+        assert_eq!(remaining.keys_vec(), range(0u64, 30).chain(range(60u64, 100)).collect());
+
+        let (all, none) = tree.clone().extract_range(&200, &300);
+        assert_eq!(all.len, 100);
+        assert_eq!(none.len, 0);
+
+        let (none, all) = tree.clone().extract_range(&0, &100);
+        assert_eq!(none.len, 0);
+        assert_eq!(all.len, 100);
+    }
+
+    #[test]
+    fn test_splice_range() {
+        let tree = build(range(0u64, 100).map(|x| x * 2).collect::<Vec<u64>>().as_slice());
+        let replacement = build(range(0u64, 30).map(|x| 51 + x * 3).collect::<Vec<u64>>()
+                                                                    .as_slice());
+
+        let spliced = tree.clone().splice_range(&50, &150, replacement.clone());
+        assert!(spliced.check_invariants());
+        assert_eq!(spliced.len, 25 + 30 + 25);
+
+        let expected: Vec<u64> = range(0u64, 25).map(|x| x * 2)
+                                                .chain(range(0u64, 30).map(|x| 51 + x * 3))
+                                                .chain(range(75u64, 100).map(|x| x * 2))
+                                                .collect();
+        assert_eq!(spliced.iter().map(|(k, _)| *k).collect::<Vec<u64>>(), expected);
+
+        // Splicing in an empty tree removes the range, an empty range only inserts
+        let removed = tree.clone().splice_range(&50, &150, build([]));
+        assert!(removed.check_invariants());
+        assert_eq!(removed.len, 50);
+
+        let inserted = tree.clone().splice_range(&51, &52, build([51]));
+        assert!(inserted.check_invariants());
+        assert_eq!(inserted.len, 101);
+        assert_eq!(inserted.find(&51), Some(&51));
+    }
+
+    #[test]
+    fn test_allocated_node_count_after_removal() {
+        let keys: Vec<u64> = range(0u64, 1000).map(|x| x * 7919 % 1000).collect();
+        let mut tree = build(keys.as_slice());
+        assert_eq!(tree.allocated_node_count(), 1000);
+
+        for &key in keys.iter().filter(|&&k| k % 2 == 0) {
+            tree = tree.remove(&key).val0();
+            assert_eq!(tree.allocated_node_count(), tree.len);
+        }
+
+        assert_eq!(tree.len, 500);
+        assert!(tree.check_invariants());
+        assert_eq!(build([]).allocated_node_count(), 0);
+    }
+
+    #[test]
+    fn test_min_max_key_value() {
+        let tree = build([50, 20, 80, 10, 30, 70, 90, 5]);
+        assert_eq!(tree.min_key_value(), Some((&5, &5)));
+        assert_eq!(tree.max_key_value(), Some((&90, &90)));
+
+        let single = build([7]);
+        assert_eq!(single.min_key_value(), Some((&7, &7)));
+        assert_eq!(single.max_key_value(), Some((&7, &7)));
+
+        assert_eq!(build([]).min_key_value(), None);
+        assert_eq!(build([]).max_key_value(), None);
+    }
+
+    #[test]
+    fn test_remove_min() {
+        let keys: Vec<u64> = range(0u64, 500).map(|x| x * 7919 % 1000).collect();
+        let mut tree = build(keys.as_slice());
+        let mut sorted = keys.clone();
+        sorted.sort();
+
+        for &expected in sorted.iter() {
+            let (rest, removed) = tree.remove_min();
+            assert_eq!(removed, Some((expected, expected)));
+            assert!(rest.balanced());
+            assert!(rest.no_red_red());
+            assert!(rest.check_invariants());
+            assert_eq!(rest.min_key_value().map(|(k, _)| *k > expected),
+                       if rest.len > 0 { Some(true) } else { None });
+            tree = rest;
+        }
+
+        assert_eq!(tree.len, 0);
+        let (empty, removed) = tree.remove_min();
+        assert_eq!(removed, None);
+        assert_eq!(empty.len, 0);
+    }
+
+    #[test]
+    fn test_remove_with_value() {
+        let mut tree = build([]);
+        for key in range(0u64, 100) {
+            tree = tree.insert(CopyStore::new(key, key * 10)).val0();
+        }
+
+        let (tree, removed) = tree.remove_with_value(&42);
+        assert_eq!(removed, Some(420));
+        assert_eq!(tree.len, 99);
+        assert_eq!(tree.find(&42), None);
+        assert!(tree.check_invariants());
+
+        let (tree, removed) = tree.remove_with_value(&42);
+        assert_eq!(removed, None);
+        assert_eq!(tree.len, 99);
+
+        let (tree, removed) = tree.remove_with_value(&1000);
+        assert_eq!(removed, None);
+        assert_eq!(tree.len, 99);
+
+        let (empty, removed) = build([]).remove_with_value(&1);
+        assert_eq!(removed, None);
+        assert_eq!(empty.len, 0);
+    }
+
+    #[test]
+    fn test_insert_with_old() {
+        let tree = build([1, 2, 3]);
+
+        let (tree, old) = tree.insert_with_old(4, 40);
+        assert_eq!(old, None);
+        assert_eq!(tree.len, 4);
+        assert_eq!(tree.find(&4), Some(&40));
+
+        let (tree, old) = tree.insert_with_old(2, 20);
+        assert_eq!(old, Some(2));
+        assert_eq!(tree.len, 4);
+        assert_eq!(tree.find(&2), Some(&20));
+
+        let (tree, old) = tree.insert_with_old(2, 200);
+        assert_eq!(old, Some(20));
+        assert_eq!(tree.len, 4);
+        assert!(tree.check_invariants());
+    }
+
+    #[test]
+    fn test_floor() {
+        let tree = build([10, 20, 30]);
+        assert_eq!(tree.floor(&5), None);
+        assert_eq!(tree.floor(&10), Some((&10, &10)));
+        assert_eq!(tree.floor(&29), Some((&20, &20)));
+        assert_eq!(tree.floor(&100), Some((&30, &30)));
+        assert_eq!(build([]).floor(&1), None);
+    }
+
+    #[test]
+    fn test_step_map() {
+        let steps: StepMap<u64, &'static str, CopyStore<u64, &'static str>> = StepMap::new();
+        let steps = steps.set_range(0, "low")
+                         .set_range(50, "medium")
+                         .set_range(80, "high")
+                         .set_range(100, "off");
+
+        assert_eq!(steps.len(), 4);
+        assert_eq!(steps.lookup(&0), Some(&"low"));
+        assert_eq!(steps.lookup(&49), Some(&"low"));
+        assert_eq!(steps.lookup(&50), Some(&"medium"));
+        assert_eq!(steps.lookup(&79), Some(&"medium"));
+        assert_eq!(steps.lookup(&80), Some(&"high"));
+        assert_eq!(steps.lookup(&1000), Some(&"off"));
+
+        let shifted = steps.clone().set_range(20, "rising").set_range(50, "steady");
+        assert_eq!(shifted.lookup(&10), Some(&"low"));
+        assert_eq!(shifted.lookup(&30), Some(&"rising"));
+        assert_eq!(shifted.lookup(&60), Some(&"steady"));
+        assert_eq!(steps.lookup(&30), Some(&"low"));
+
+        let later = StepMap::<u64, u64, CopyStore<u64, u64>>::new().set_range(10, 1);
+        assert_eq!(later.lookup(&9), None);
+        assert_eq!(later.lookup(&10), Some(&1));
+    }
+
+    #[test]
+    fn test_aggregate() {
+        let tree = build([3, 1, 4, 5, 9, 2, 6]);
+
+        let product = tree.aggregate(1u64, |&v| v, |a, b| a * b);
+        assert_eq!(product, 3 * 1 * 4 * 5 * 9 * 2 * 6);
+
+        let max = tree.aggregate(None, |&v| Some(v), |a, b| ::std::cmp::max(a, b));
+        assert_eq!(max, Some(9));
+
+        assert_eq!(build([]).aggregate(1u64, |&v| v, |a, b| a * b), 1);
+        assert_eq!(build([]).aggregate(None, |&v| Some(v), |a, b| ::std::cmp::max(a, b)), None);
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::RedBlackTree;
-    use testing::Test;
-    use test::Bencher;
-    use item_store::{CopyStore, ShareStore};
+    #[test]
+    fn test_nth_successor() {
+        let tree = build(range(0u64, 100).map(|x| x * 2).collect::<Vec<u64>>().as_slice());
+
+        assert_eq!(tree.nth_successor(&10, 0), Some((&12, &12)));
+        assert_eq!(tree.nth_successor(&10, 3), Some((&18, &18)));
+        assert_eq!(tree.nth_successor(&11, 3), Some((&18, &18)));
+        assert_eq!(tree.nth_successor(&0, 98), Some((&198, &198)));
+        assert_eq!(tree.nth_successor(&0, 99), None);
+        assert_eq!(tree.nth_successor(&192, 3), None);
+        assert_eq!(tree.nth_successor(&198, 0), None);
+        assert_eq!(build([]).nth_successor(&1, 0), None);
+
+        for key in range(0u64, 200) {
+            for n in range(0u, 5) {
+                let expected = range(0u64, 100).map(|x| x * 2).filter(|&x| x > key).nth(n);
+                assert_eq!(tree.nth_successor(&key, n).map(|(k, _)| *k), expected);
+            }
+        }
+    }
+
+    #[test]
+    fn test_read_only_map_object() {
+        // Imported here only, so that `len()` on trees elsewhere in the tests keeps resolving to
+        // `Collection::len()`
+        use ReadOnlyMap;
+
+        let tree = build([1, 3, 5, 7]);
+        let map: Arc<Box<ReadOnlyMap<u64, u64> + Send + Sync>> = Arc::new(box tree.clone());
+
+        assert_eq!(map.len(), 4);
+        assert_eq!(map.get(&3), Some(&3));
+        assert_eq!(map.get(&4), None);
+        assert!(map.contains(&7));
+        assert!(!map.contains(&8));
+
+        // The tree behind the trait object is unaffected by later versions of the original
+        let tree = tree.insert(CopyStore::new(4, 4)).val0();
+        assert_eq!(tree.find(&4), Some(&4));
+        assert_eq!(map.get(&4), None);
+    }
+
+    #[test]
+    fn test_height_sequential_inserts() {
+        let count = 100000u64;
+        let max_height = 2.0 * (count as f64 + 1.0).log2();
+
+        let mut ascending = build([]);
+        for key in range(0u64, count) {
+            ascending = ascending.insert(CopyStore::new(key, key)).val0();
+        }
+        assert!(ascending.height() as f64 <= max_height);
+        assert!(ascending.check_invariants());
+
+        let mut descending = build([]);
+        for key in range(0u64, count).rev() {
+            descending = descending.insert(CopyStore::new(key, key)).val0();
+        }
+        assert!(descending.height() as f64 <= max_height);
+        assert!(descending.check_invariants());
+
+        assert_eq!(build([]).height(), 0);
+        assert_eq!(build([1]).height(), 1);
+    }
+
+    #[test]
+    fn test_add_counts() {
+        let mut left = build([]);
+        for &(key, count) in [(1u64, 2u64), (3, 1), (5, 7)].iter() {
+            left = left.insert(CopyStore::new(key, count)).val0();
+        }
+        let mut right = build([]);
+        for &(key, count) in [(2u64, 4u64), (3, 3), (5, 1), (8, 1)].iter() {
+            right = right.insert(CopyStore::new(key, count)).val0();
+        }
+
+        let sum = left.clone().add_counts(&right);
+        assert!(sum.check_invariants());
+        let entries: Vec<(u64, u64)> = sum.iter().map(|(k, v)| (*k, *v)).collect();
+        assert_eq!(entries, vec![(1, 2), (2, 4), (3, 4), (5, 8), (8, 1)]);
+
+        assert!(left.clone().add_counts(&build([])).eq_by(&left, |a, b| *a == *b));
+        assert!(build([]).add_counts(&right).eq_by(&right, |a, b| *a == *b));
+    }
+
+    #[test]
+    fn test_keys_and_values() {
+        let mut tree = build(range(0u64, 200).map(|x| x * 7919 % 500).collect::<Vec<u64>>()
+                                                                    .as_slice());
+        for key in range(0u64, 50) {
+            tree = tree.insert(CopyStore::new(key * 10, 100000)).val0();
+            tree = tree.insert(CopyStore::new(key * 10, key + 1000)).val0();
+        }
+
+        assert_eq!(tree.keys().size_hint(), (tree.len, Some(tree.len)));
+        assert_eq!(tree.values().count(), tree.len);
+        for ((key, val), (expected_key, expected_val)) in
+                tree.keys().zip(tree.values()).zip(tree.iter()) {
+            assert!(key as *const u64 == expected_key as *const u64);
+            assert!(val as *const u64 == expected_val as *const u64);
+        }
+
+        // Only the latest value of an overwritten key remains
+        assert_eq!(tree.values().filter(|&&v| v == 100000).count(), 0);
+        assert_eq!(tree.values().filter(|&&v| v >= 1000).count(), 50);
+
+        assert_eq!(build([]).keys().next(), None);
+        assert_eq!(build([]).values().next(), None);
+    }
+
+    #[test]
+    fn test_keys_vec() {
+        let keys: Vec<u64> = range(0u64, 300).map(|x| x * 7919 % 100).collect();
+        let tree = build(keys.as_slice());
+
+        let sorted = tree.keys_vec();
+        assert_eq!(sorted.len(), tree.len);
+        assert_eq!(sorted, range(0u64, 100).collect());
+        assert!(sorted.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(build([]).keys_vec().is_empty());
+    }
+
+    #[test]
+    fn test_into_columns() {
+        let mut tree = build([]);
+        for &key in [42u64, 7, 19, 3, 88].iter() {
+            tree = tree.insert(CopyStore::new(key, key * 10)).val0();
+        }
+
+        let (keys, vals) = tree.into_columns();
+        assert_eq!(keys, vec![3, 7, 19, 42, 88]);
+        for (key, val) in keys.iter().zip(vals.iter()) {
+            assert_eq!(*val, *key * 10);
+        }
+
+        let (keys, vals) = build([]).into_columns();
+        assert!(keys.is_empty());
+        assert!(vals.is_empty());
+    }
+
+    #[test]
+    fn test_is_submap_of() {
+        let base = build([1, 2, 3, 4, 5, 6]);
+
+        assert!(build([2, 4, 6]).is_submap_of(&base));
+        assert!(base.is_submap_of(&base));
+        assert!(build([]).is_submap_of(&base));
+        assert!(build([]).is_submap_of(&build([])));
+
+        assert!(!build([2, 7]).is_submap_of(&base));
+        assert!(!build([0, 2]).is_submap_of(&base));
+        assert!(!base.is_submap_of(&build([2, 4, 6])));
+
+        let changed = build([2, 4]).insert(CopyStore::new(4, 40)).val0();
+        assert!(!changed.is_submap_of(&base));
+    }
+
+    #[test]
+    fn test_retain_count() {
+        let tree = build(range(0u64, 100).collect::<Vec<u64>>().as_slice());
+
+        let (survivors, dropped) = tree.clone().retain_count(|&k, _| k % 3 == 0);
+        assert!(survivors.check_invariants());
+        assert_eq!(survivors.len, 34);
+        assert_eq!(dropped, tree.len - survivors.len);
+        assert!(survivors.iter().all(|(k, _)| *k % 3 == 0));
+
+        let (all, dropped) = tree.clone().retain_count(|_, _| true);
+        assert_eq!(dropped, 0);
+        assert!(all.eq_by(&tree, |a, b| *a == *b));
+
+        let retained = tree.clone().retain(|_, &v| v >= 90);
+        assert_eq!(retained.iter().map(|(k, _)| *k).collect::<Vec<u64>>(),
+                   range(90u64, 100).collect());
+    }
+
+    #[test]
+    fn test_with_inserted() {
+        let original = build(range(0u64, 1000).collect::<Vec<u64>>().as_slice());
+        let derived = original.with_inserted(5000, 1);
+        let overwritten = original.with_inserted(10, 100);
+
+        assert_eq!(original.len, 1000);
+        assert_eq!(original.find(&5000), None);
+        assert_eq!(original.find(&10), Some(&10));
+        assert_eq!(derived.len, 1001);
+        assert_eq!(derived.find(&5000), Some(&1));
+        assert_eq!(overwritten.len, 1000);
+        assert_eq!(overwritten.find(&10), Some(&100));
+
+        let report = RedBlackTree::sharing_report([original.clone(), derived.clone()]);
+        // The derived version only adds copies of the nodes on the insertion path
+        assert!(report.distinct_nodes <= 1001 + derived.height());
+    }
+
+    #[test]
+    fn test_merge_all() {
+        let mut trees = Vec::new();
+        for (index, keys) in [[1u64, 4, 7, 10], [2, 4, 8, 10], [0, 4, 9, 12]].iter().enumerate() {
+            let mut tree = build([]);
+            for &key in keys.iter() {
+                tree = tree.insert(CopyStore::new(key, index as u64)).val0();
+            }
+            trees.push(tree);
+        }
+
+        let merged: Vec<(u64, u64)> = RedBlackTree::merge_all(trees.as_slice())
+            .map(|(k, v)| (*k, *v))
+            .collect();
+
+        assert_eq!(merged, vec![(0, 2), (1, 0), (2, 1), (4, 0), (4, 1), (4, 2), (7, 0), (8, 1),
+                                (9, 2), (10, 0), (10, 1), (12, 2)]);
+
+        let none: Vec<RedBlackTree<u64, u64, CopyStore<u64, u64>>> = Vec::new();
+        assert_eq!(RedBlackTree::merge_all(none.as_slice()).count(), 0);
+    }
+
+    #[test]
+    fn test_numeric_diff() {
+        fn series(entries: &[(u64, f64)]) -> RedBlackTree<u64, f64, CopyStore<u64, f64>> {
+            let mut tree = RedBlackTree::new();
+            for &(key, val) in entries.iter() {
+                tree = tree.insert(CopyStore::new(key, val)).val0();
+            }
+            tree
+        }
+
+        let current = series([(1, 10.0), (2, 5.5), (4, 3.0)]);
+        let previous = series([(2, 2.5), (3, 1.0), (4, 3.0)]);
+
+        let delta = current.numeric_diff(&previous);
+        assert!(delta.check_invariants());
+        let entries: Vec<(u64, f64)> = delta.iter().map(|(k, v)| (*k, *v)).collect();
+        assert_eq!(entries, vec![(1, 10.0), (2, 3.0), (3, -1.0), (4, 0.0)]);
+
+        assert_eq!(series([]).numeric_diff(&series([])).len, 0);
+    }
+
+    #[test]
+    fn test_page() {
+        let tree = build(range(0u64, 100).map(|x| x * 7919 % 100).collect::<Vec<u64>>()
+                                                                  .as_slice());
+
+        for page_number in range(0u, 10) {
+            let keys: Vec<u64> = tree.page(page_number, 10).iter().map(|&(k, _)| *k).collect();
+            let first = page_number as u64 * 10;
+            assert_eq!(keys, range(first, first + 10).collect());
+        }
+
+        assert_eq!(tree.page(3, 30).len(), 10);
+        assert_eq!(tree.page(10, 10).len(), 0);
+
+        // Seeking only compares keys along a few root-to-leaf paths, regardless of the page
+        let counter = CmpCounter::new();
+        let mut counted: RedBlackTree<CountingCmp<u64>, u64, CopyStore<CountingCmp<u64>, u64>> =
+            RedBlackTree::new();
+        for key in range(0u64, 10000) {
+            counted = counted.insert(CopyStore::new(counter.key(key), key)).val0();
+        }
+        counter.reset();
+        assert_eq!(counted.page(900, 10).len(), 10);
+        assert!(counter.count() <= 4 * counted.height());
+        assert_eq!(tree.page(0, 0).len(), 0);
+        assert_eq!(build([]).page(0, 10).len(), 0);
+    }
+
+    #[test]
+    fn test_window_from_rank() {
+        let tree = build(range(0u64, 10).map(|x| x * 10).collect::<Vec<u64>>().as_slice());
+        fn keys(window: Vec<(&u64, &u64)>) -> Vec<u64> {
+            window.iter().map(|&(k, _)| *k).collect()
+        }
+
+        assert_eq!(keys(tree.window_from_rank(8, 4)), vec![80, 90, 0, 10]);
+        assert_eq!(keys(tree.window_from_rank(2, 3)), vec![20, 30, 40]);
+        assert_eq!(keys(tree.window_from_rank(12, 2)), vec![20, 30]);
+        assert_eq!(keys(tree.window_from_rank(9, 12)).len(), 12);
+        assert_eq!(keys(tree.window_from_rank(0, 0)).len(), 0);
+        assert_eq!(build([]).window_from_rank(3, 5).len(), 0);
+    }
+
+    #[test]
+    fn test_map_values_with_policy() {
+        let tree = build(range(0u64, 1000).collect::<Vec<u64>>().as_slice());
+
+        // Changing a single value only copies the path to it
+        let shared = tree.map_values_with_policy(ShareUnchanged, |&v| if v == 500 { 0 } else { v });
+        assert!(shared.check_invariants());
+        assert!(shared.structurally_eq(&tree));
+        assert_eq!(shared.find(&500), Some(&0));
+        assert_eq!(shared.find(&499), Some(&499));
+        let report = RedBlackTree::sharing_report([tree.clone(), shared.clone()]);
+        assert!(report.distinct_nodes <= 1000 + shared.height());
+
+        // Leaving all values alone shares the whole tree
+        let identical = tree.map_values_with_policy(ShareUnchanged, |&v| v);
+        assert!(identical.root.node_ptr() == tree.root.node_ptr());
+
+        let copied = tree.map_values_with_policy(CopyAll, |&v| v);
+        assert!(copied.check_invariants());
+        assert!(copied.structurally_eq(&tree));
+        assert!(copied.eq_by(&tree, |a, b| *a == *b));
+        let report = RedBlackTree::sharing_report([tree.clone(), copied.clone()]);
+        assert_eq!(report.distinct_nodes, 2000);
+    }
+
+    #[test]
+    fn test_shift_keys() {
+        let keys: Vec<u64> = range(0u64, 200).map(|x| x * 7919 % 1000).collect();
+        let tree = build(keys.as_slice());
+
+        let shifted = tree.shift_keys(|&k| k + 100);
+        assert!(shifted.check_invariants());
+        assert_eq!(shifted.len, tree.len);
+        assert_eq!(shifted.height(), tree.height());
+        assert_eq!(shifted.black_height(), tree.black_height());
+
+        for ((old_key, old_val), (new_key, new_val)) in tree.iter().zip(shifted.iter()) {
+            assert_eq!(*new_key, *old_key + 100);
+            assert_eq!(*new_val, *old_val);
+        }
+
+        // Shifting back yields the same shape and keys as the original
+        assert!(shifted.shift_keys(|&k| k - 100).structurally_eq(&tree));
+    }
+
+    // The check is a `debug_assert!()`, so it is compiled out with `--cfg ndebug`
+    #[test]
+    #[should_fail]
+    #[cfg(not(ndebug))]
+    fn test_shift_keys_rejects_decreasing_transform() {
+        build([1, 2, 3]).shift_keys(|&k| 10 - k);
+    }
+
+    #[test]
+    fn test_shares_nodes_with() {
+        let tree = build(range(0u64, 100).collect::<Vec<u64>>().as_slice());
+
+        let rebuilt = RedBlackTree::from_sorted(tree.iter().map(|(k, v)| CopyStore::new(*k, *v))
+                                                           .collect());
+        assert!(rebuilt.eq_by(&tree, |a, b| *a == *b));
+        assert!(!rebuilt.shares_nodes_with(&tree));
+        assert!(!tree.shares_nodes_with(&rebuilt));
+
+        assert!(tree.clone().shares_nodes_with(&tree));
+        assert!(tree.with_inserted(1000, 0).shares_nodes_with(&tree));
+        assert!(!build([]).shares_nodes_with(&tree));
+    }
+
+    #[test]
+    fn test_from_sorted_dedup() {
+        let events = vec![(1u64, 1u64), (1, 2), (2, 5), (4, 1), (4, 1), (4, 1), (7, 3), (9, 2),
+                          (9, 8)];
+        let tree = RedBlackTree::<u64, u64, CopyStore<u64, u64>>::from_sorted_dedup(
+            events.move_iter(), |total, count| *total += count);
+
+        assert!(tree.check_invariants());
+        assert_eq!(tree.allocated_node_count(), 5);
+        let entries: Vec<(u64, u64)> = tree.iter().map(|(k, v)| (*k, *v)).collect();
+        assert_eq!(entries, vec![(1, 3), (2, 5), (4, 3), (7, 3), (9, 10)]);
+
+        let empty = RedBlackTree::<u64, u64, CopyStore<u64, u64>>::from_sorted_dedup(
+            Vec::new().move_iter(), |total, count| *total += count);
+        assert_eq!(empty.len, 0);
+
+        // Keys that are `Ord`-equal but `Eq`-distinct still form one run; the first key is kept
+        let loose = vec![(tagged(1, 0), 1u64), (tagged(1, 1), 2), (tagged(2, 0), 5)];
+        let tree = RedBlackTree::<TaggedKey, u64, CopyStore<TaggedKey, u64>>::from_sorted_dedup(
+            loose.move_iter(), |total, count| *total += count);
+        assert!(tree.check_invariants());
+        let entries: Vec<(u64, u64, u64)> = tree.iter().map(|(k, v)| (k.id, *k.tag, *v)).collect();
+        assert_eq!(entries, vec![(1, 0, 3), (2, 0, 5)]);
+    }
+
+    #[test]
+    fn test_partition_point() {
+        let tree = build(range(0u64, 40).map(|x| x * 3).collect::<Vec<u64>>().as_slice());
+
+        assert_eq!(tree.partition_point(|k| *k >= 50), Some((&51, &51)));
+        assert_eq!(tree.partition_point(|k| *k >= 51), Some((&51, &51)));
+        assert_eq!(tree.partition_point(|k| *k >= 1000), None);
+        assert_eq!(tree.partition_point(|_| true), Some((&0, &0)));
+        assert_eq!(build([]).partition_point(|_| true), None);
+    }
+
+    #[test]
+    fn test_union_tracked() {
+        let left = build([1, 3, 5, 7]);
+        let right = build([2, 3, 6, 7, 8]).insert(CopyStore::new(3, 30)).val0();
+
+        let merged: RedBlackTree<u64, (u64, Source), CopyStore<u64, (u64, Source)>> =
+            left.union_tracked(right);
+        assert!(merged.check_invariants());
+
+        let entries: Vec<(u64, (u64, Source))> = merged.iter().map(|(k, v)| (*k, *v)).collect();
+        assert_eq!(entries, vec![(1, (1, Left)), (2, (2, Right)), (3, (3, Both)), (5, (5, Left)),
+                                 (6, (6, Right)), (7, (7, Both)), (8, (8, Right))]);
+    }
+
+    #[test]
+    fn test_set_interned() {
+        let states = [Arc::new("idle".to_string()),
+                      Arc::new("running".to_string()),
+                      Arc::new("stopped".to_string())];
+        let mut tree = RedBlackTree::<u64, String, InternedValueStore<u64, String>>::new();
+
+        for key in range(0u64, 1000) {
+            tree = tree.set_interned(key, states[(key % 3) as uint].clone()).val0();
+        }
+
+        assert_eq!(tree.len, 1000);
+        assert!(tree.check_invariants());
+        assert_eq!(tree.find(&4).map(|s| s.as_slice()), Some("running"));
+
+        let mut allocations = HashSet::new();
+        for (_, val) in tree.iter() {
+            allocations.insert(val as *const String as uint);
+        }
+        assert_eq!(allocations.len(), 3);
+    }
+
+    #[test]
+    fn test_intersection_count() {
+        let evens = build(range(0u64, 100).map(|x| x * 2).collect::<Vec<u64>>().as_slice());
+        let threes = build(range(0u64, 100).map(|x| x * 3).collect::<Vec<u64>>().as_slice());
+        let odds = build(range(0u64, 100).map(|x| x * 2 + 1).collect::<Vec<u64>>().as_slice());
+
+        // Multiples of 6 below 198
+        assert_eq!(evens.intersection_count(&threes), 34);
+        assert_eq!(threes.intersection_count(&evens), 34);
+        assert_eq!(evens.intersection_count(&odds), 0);
+        assert_eq!(evens.intersection_count(&evens), evens.len);
+        assert_eq!(evens.intersection_count(&build([])), 0);
+    }
+
+    #[test]
+    fn test_move_iter() {
+        let empty = build([]);
+        assert_eq!(empty.move_iter().count(), 0);
+
+        let single = build([42]);
+        let mut owned = Vec::new();
+        for (key, val) in single.move_iter() {
+            owned.push((key, val));
+        }
+        assert_eq!(owned, vec![(42, 42)]);
+
+        // Nodes shared with another version stay intact while an owned iterator walks them
+        let tree = build([1, 2, 3]);
+        let version = tree.clone();
+        let mut entries = tree.move_iter();
+        assert_eq!(entries.next(), Some((1, 1)));
+        drop(version);
+        assert_eq!(entries.collect::<Vec<(u64, u64)>>(), vec![(2, 2), (3, 3)]);
+
+        let borrowed = build([7]);
+        let mut count = 0u;
+        for (key, val) in borrowed.iter() {
+            assert_eq!((*key, *val), (7, 7));
+            count += 1;
+        }
+        assert_eq!(count, 1);
+    }
+
+    #[test]
+    fn test_into_sorted_iter() {
+        let keys: Vec<u64> = range(0u64, 300).map(|x| x * 7919 % 1000).collect();
+        let tree = build(keys.as_slice());
+        let mut sorted = keys.clone();
+        sorted.sort();
+
+        let mut entries = tree.into_sorted_iter();
+        assert_eq!(entries.size_hint(), (300, Some(300)));
+        assert_eq!(entries.next(), Some((sorted[0], sorted[0])));
+        assert_eq!(entries.size_hint(), (299, Some(299)));
+
+        let rest: Vec<(u64, u64)> = entries.collect();
+        assert_eq!(rest, sorted.iter().skip(1).map(|&k| (k, k)).collect());
+
+        assert_eq!(build([]).into_sorted_iter().next(), None);
+    }
+
+    #[test]
+    fn test_len_matches_structure() {
+        let mut rng = StdRng::new().ok().expect("Could not create random number generator");
+        let mut tree = build([]);
+        assert!(tree.len_matches_structure());
+
+        for _ in range(0u, 5000) {
+            let key = rng.gen_range(0u64, 500);
+            tree = if rng.gen_weighted_bool(3) {
+                tree.remove(&key).val0()
+            } else {
+                tree.insert(CopyStore::new(key, key)).val0()
+            };
+            assert!(tree.len_matches_structure());
+        }
+
+        assert!(tree.check_invariants());
+    }
+
+    #[test]
+    fn test_scan() {
+        let mut tree = build([]);
+        for &(key, val) in [(5u64, 2u64), (1, 10), (9, 1), (3, 4)].iter() {
+            tree = tree.insert(CopyStore::new(key, val)).val0();
+        }
+
+        let sums: RedBlackTree<u64, u64, CopyStore<u64, u64>> =
+            tree.scan(0, |&sum, _, &val| sum + val);
+        assert!(sums.check_invariants());
+        let entries: Vec<(u64, u64)> = sums.iter().map(|(k, v)| (*k, *v)).collect();
+        assert_eq!(entries, vec![(1, 10), (3, 14), (5, 16), (9, 17)]);
+
+        let empty: RedBlackTree<u64, u64, CopyStore<u64, u64>> =
+            build([]).scan(0, |&sum, _, &val| sum + val);
+        assert_eq!(empty.len, 0);
+    }
+
+    #[test]
+    fn test_is_derived_from() {
+        let source = build(range(0u64, 100).collect::<Vec<u64>>().as_slice());
+        let derived = source.with_inserted(100, 100);
+        let independent = build(range(0u64, 101).collect::<Vec<u64>>().as_slice());
+
+        assert!(derived.eq_by(&independent, |a, b| *a == *b));
+        assert!(derived.is_derived_from(&source));
+        assert!(source.is_derived_from(&derived));
+        assert!(!independent.is_derived_from(&source));
+    }
+
+    #[test]
+    fn test_level_order() {
+        let tree = build_from_sorted(range(0u64, 15).collect::<Vec<u64>>().as_slice());
+        let levels = tree.level_order();
+
+        assert_eq!(levels.len(), 15);
+        let keys: Vec<u64> = levels.iter().map(|&(_, _, k, _)| *k).collect();
+        assert_eq!(keys, vec![7, 3, 11, 1, 5, 9, 13, 0, 2, 4, 6, 8, 10, 12, 14]);
+
+        let (root_depth, root_color, _, _) = levels[0];
+        assert_eq!(root_depth, 0);
+        assert!(root_color == Black);
+        assert!(levels.windows(2).all(|pair| pair[0].val0() <= pair[1].val0()));
+
+        let random = build(range(0u64, 500).map(|x| x * 7919 % 1000).collect::<Vec<u64>>()
+                                                                     .as_slice());
+        let levels = random.level_order();
+        assert_eq!(levels.len(), 500);
+        assert!(levels.windows(2).all(|pair| pair[0].val0() <= pair[1].val0()));
+        assert_eq!(levels.last().unwrap().val0() + 1, random.height());
+
+        assert_eq!(build([]).level_order().len(), 0);
+    }
+
+    #[test]
+    fn test_remove_last_key_resets_root() {
+        let fresh = build([]);
+
+        for &iterative in [false, true].iter() {
+            let mut tree = build([5, 3, 8, 1]);
+            for key in [3u64, 8, 1, 5].iter() {
+                tree = if iterative {
+                    tree.remove_iterative(key).val0()
+                } else {
+                    tree.remove(key).val0()
+                };
+                assert!(tree.check_invariants());
+            }
+
+            assert_eq!(tree.len, 0);
+            assert!(tree.root.is_leaf());
+            assert!(tree.root.col == fresh.root.col);
+            assert!(tree.root.col == Black);
+            assert!(tree.structurally_eq(&fresh));
+        }
+    }
+
+    #[test]
+    fn test_zip_all() {
+        let left = build([1, 3, 5, 7]);
+        let mut right = RedBlackTree::<u64, &'static str, CopyStore<u64, &'static str>>::new();
+        for &(key, val) in [(2u64, "two"), (3, "three"), (7, "seven"), (9, "nine")].iter() {
+            right = right.insert(CopyStore::new(key, val)).val0();
+        }
+
+        let zipped: Vec<(u64, Option<u64>, Option<&'static str>)> =
+            left.zip_all(&right).map(|(k, l, r)| (*k, l.map(|v| *v), r.map(|v| *v))).collect();
+        assert_eq!(zipped, vec![(1, Some(1), None),
+                                (2, None, Some("two")),
+                                (3, Some(3), Some("three")),
+                                (5, Some(5), None),
+                                (7, Some(7), Some("seven")),
+                                (9, None, Some("nine"))]);
+
+        assert!(left.zip_all(&left).all(|(k, l, r)| l == Some(k) && r == Some(k)));
+        assert_eq!(left.zip_all(&build([])).count(), 4);
+        assert_eq!(build([]).zip_all(&right).count(), 4);
+        assert_eq!(build([]).zip_all(&build([])).count(), 0);
+    }
+
+    #[test]
+    fn test_node_color_counts() {
+        for &len in [0u, 1, 7, 15, 20, 100, 1000].iter() {
+            let tree = build_from_sorted(range(0u64, len as u64).collect::<Vec<u64>>().as_slice());
+            assert_eq!(tree.red_node_count() + tree.black_node_count(), len);
+
+            // The complete levels are black, the deepest incomplete level is red
+            let complete = (1u << max_black_height_for(len)) - 1;
+            assert_eq!(tree.black_node_count(), complete);
+            assert_eq!(tree.red_node_count(), len - complete);
+            for &(depth, color, _, _) in tree.level_order().iter() {
+                assert!((color == Red) == (depth >= max_black_height_for(len)));
+            }
+        }
+
+        let incremental = build(range(0u64, 1000).collect::<Vec<u64>>().as_slice());
+        assert_eq!(incremental.red_node_count() + incremental.black_node_count(), 1000);
+    }
+
+    #[test]
+    fn test_coalesce() {
+        // Intervals [start, end), keyed by their start
+        let mut intervals = build([]);
+        for &(start, end) in [(0u64, 3u64), (3, 5), (4, 9), (12, 14), (20, 22), (22, 30)].iter() {
+            intervals = intervals.insert(CopyStore::new(start, end)).val0();
+        }
+
+        let merged = intervals.coalesce(|&start, &end, &next_start, &next_end| {
+            if next_start <= end {
+                Some((start, ::std::cmp::max(end, next_end)))
+            } else {
+                None
+            }
+        });
+
+        assert!(merged.check_invariants());
+        let entries: Vec<(u64, u64)> = merged.iter().map(|(k, v)| (*k, *v)).collect();
+        assert_eq!(entries, vec![(0, 9), (12, 14), (20, 30)]);
+
+        let unchanged = intervals.coalesce(|_, _, _, _| None);
+        assert!(unchanged.eq_by(&intervals, |a, b| *a == *b));
+        assert_eq!(build([]).coalesce(|_, _, _, _| None).len, 0);
+    }
+
+    #[test]
+    fn test_bulk_build_is_optimally_shaped() {
+        let keys: Vec<u64> = range(0u64, 1023).collect();
+        let bulk = build_from_sorted(keys.as_slice());
+        let incremental = build(keys.as_slice());
+
+        // 1023 keys fill a complete binary tree of height log2(1024) exactly
+        assert!(bulk.check_invariants());
+        assert_eq!(bulk.height(), 10);
+        assert_eq!(bulk.black_height(), 10);
+        assert_eq!(bulk.red_node_count(), 0);
+
+        assert!(incremental.check_invariants());
+        assert!(incremental.height() >= bulk.height());
+        assert!(incremental.eq_by(&bulk, |a, b| *a == *b));
+
+        // Any other length gets the minimal height, too
+        for len in range(1u, 300) {
+            let tree = build_from_sorted(range(0u64, len as u64).collect::<Vec<u64>>().as_slice());
+            // A tree of height `h` holds at most `2^h - 1` entries
+            assert_eq!(tree.height(), max_black_height_for(len - 1) + 1);
+        }
+    }
+
+    #[test]
+    fn test_split_at_rank() {
+        let tree = build(range(0u64, 100).collect::<Vec<u64>>().as_slice());
+
+        for index in range(0u, 102) {
+            let (left, right) = tree.clone().split_at_rank(index);
+            assert!(left.check_invariants());
+            assert!(right.check_invariants());
+            let split = ::std::cmp::min(index, 100) as u64;
+            assert_eq!(left.iter().map(|(k, _)| *k).collect::<Vec<u64>>(),
+                       range(0u64, split).collect());
+            assert_eq!(right.iter().map(|(k, _)| *k).collect::<Vec<u64>>(),
+                       range(split, 100).collect());
+        }
+    }
+
+    #[test]
+    fn test_remove_rank_range() {
+        let tree = build(range(0u64, 100).map(|x| x * 3).collect::<Vec<u64>>().as_slice());
+
+        let (remaining, removed) = tree.clone().remove_rank_range(10, 20);
+        assert!(remaining.check_invariants());
+        assert_eq!(remaining.len, 90);
+        assert_eq!(removed, range(10u64, 20).map(|x| (x * 3, x * 3)).collect());
+
+        for (rank, key, _) in remaining.iter_with_rank() {
+            let expected = if rank < 10 { rank } else { rank + 10 };
+            assert_eq!(*key, expected as u64 * 3);
+        }
+
+        let (unchanged, removed) = tree.clone().remove_rank_range(5, 5);
+        assert_eq!(removed.len(), 0);
+        assert!(unchanged.eq_by(&tree, |a, b| *a == *b));
+
+        let (empty, removed) = tree.clone().remove_rank_range(0, 100);
+        assert_eq!(removed.len(), 100);
+        assert_eq!(empty.len, 0);
+    }
 
     #[test]
     fn test_insert_copy() { Test::test_insert(RedBlackTree::<u64, u64, CopyStore<u64, u64>>::new()); }
@@ -680,6 +6018,16 @@ mod tests {
         Test::bench_remove(RedBlackTree::<u64, u64, CopyStore<u64, u64>>::new(), 100000, bh);
     }
 
+    #[bench]
+    fn bench_churn_copy_1000(bh: &mut Bencher) {
+        Test::bench_churn(RedBlackTree::<u64, u64, CopyStore<u64, u64>>::new(), 1000, bh);
+    }
+
+    #[bench]
+    fn bench_churn_copy_50000(bh: &mut Bencher) {
+        Test::bench_churn(RedBlackTree::<u64, u64, CopyStore<u64, u64>>::new(), 50000, bh);
+    }
+
     #[test]
     fn test_insert_shared() { Test::test_insert(RedBlackTree::<u64, u64, ShareStore<u64, u64>>::new()); }
 
@@ -733,4 +6081,14 @@ mod tests {
     fn bench_remove_shared_100000(bh: &mut Bencher) {
         Test::bench_remove(RedBlackTree::<u64, u64, ShareStore<u64, u64>>::new(), 100000, bh);
     }
+
+    #[bench]
+    fn bench_churn_shared_1000(bh: &mut Bencher) {
+        Test::bench_churn(RedBlackTree::<u64, u64, ShareStore<u64, u64>>::new(), 1000, bh);
+    }
+
+    #[bench]
+    fn bench_churn_shared_50000(bh: &mut Bencher) {
+        Test::bench_churn(RedBlackTree::<u64, u64, ShareStore<u64, u64>>::new(), 50000, bh);
+    }
 }
\ No newline at end of file
@@ -22,6 +22,7 @@ macro_rules! assert_find(
 
 static BENCH_FIND_COUNT: uint = 1000;
 static BENCH_INSERT_COUNT: uint = 1000;
+static BENCH_CHURN_COUNT: uint = 1000;
 
 pub struct Test;
 
@@ -217,6 +218,27 @@ impl<TPersistentMap: PersistentMap<u64, u64>> Test {
         })
     }
 
+    // Alternately removes an existing key and inserts a new one, so the size of the map stays
+    // at `count`
+    pub fn bench_churn(empty: TPersistentMap, count: uint, bh: &mut Bencher) {
+        let keys = create_unique_values(count + BENCH_CHURN_COUNT);
+        let mut map = empty;
+
+        for &x in keys.slice_to(count).iter() {
+            map = map.plus(x, x);
+        }
+
+        bh.iter(|| {
+            let mut map1 = map.clone();
+
+            for i in range(0u, BENCH_CHURN_COUNT) {
+                let val = keys[count + i];
+                map1 = map1.minus(&keys[i % count]);
+                map1 = map1.plus(val, val);
+            }
+        })
+    }
+
     pub fn bench_remove(empty: TPersistentMap, count: uint, bh: &mut Bencher) {
         let (map, keys) = Test::create_random_map(empty, count);
 